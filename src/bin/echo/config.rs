@@ -0,0 +1,547 @@
+use std::fs;
+use std::io;
+use std::net::{AddrParseError, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap::{Parser, ValueEnum};
+use log::LevelFilter;
+use rws::{HandshakeLimits, Keepalive, Limits, ServerConfig};
+use serde::Deserialize;
+use thiserror::Error;
+
+const DEFAULT_BIND: &str = "127.0.0.1:8080";
+
+/// Problems with the settings, reported before the server starts.
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed to read {0}: {1}")]
+    Read(PathBuf, io::Error),
+    #[error("Invalid config file {0}: {1}")]
+    Parse(PathBuf, toml::de::Error),
+    #[error("No address to listen on")]
+    NoBindAddress,
+    #[error("Invalid bind address {0:?}: {1}")]
+    Bind(String, AddrParseError),
+    #[error("Unknown log level {0:?}, expected off, error, warn, info, debug or trace")]
+    LogLevel(String),
+    #[error("{0} must be greater than 0")]
+    ZeroLimit(&'static str),
+    #[error("max_frame_size {0} exceeds max_message_size {1}")]
+    FrameLargerThanMessage(usize, usize),
+    #[error("Invalid {0} timeout {1}, expected {2} number of seconds")]
+    Timeout(&'static str, f64, &'static str),
+    #[error("A TLS certificate and key must be given together")]
+    TlsIncomplete,
+    #[error("tls_client_auth needs a CA to verify client certificates with")]
+    ClientAuthWithoutCa,
+    #[error("TLS settings given, but the server was built without the tls feature")]
+    TlsUnsupported,
+}
+
+/// What the server does with the messages it receives.
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Sends every message back.
+    #[default]
+    Echo,
+    /// Drops every message, handy for load tests.
+    Discard,
+}
+
+/// Whether clients must present a certificate once a client CA is given.
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuthMode {
+    /// Clients may connect without a certificate, one they present must still verify.
+    Optional,
+    /// Clients without a verified certificate are refused.
+    #[default]
+    Required,
+}
+
+/// WebSocket echo server.
+///
+/// Flags take precedence over the config file, which takes precedence over the defaults.
+#[derive(Parser, Debug, Default)]
+#[command(version)]
+pub struct Args {
+    /// TOML file to read settings from.
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// Address to listen on, may be given more than once [default: 127.0.0.1:8080].
+    #[arg(long)]
+    bind: Vec<String>,
+    /// What to do with received messages [default: echo].
+    #[arg(long, value_enum)]
+    handler: Option<Mode>,
+    /// One of off, error, warn, info, debug or trace [default: info].
+    #[arg(long)]
+    log_level: Option<String>,
+    /// Largest frame payload accepted from clients, in bytes [default: 16 MiB].
+    #[arg(long)]
+    max_frame_size: Option<usize>,
+    /// Largest message accepted from clients once reassembled, in bytes [default: 64 MiB].
+    #[arg(long)]
+    max_message_size: Option<usize>,
+    /// Most frames a message may be split into [default: 131072].
+    #[arg(long)]
+    max_fragments: Option<usize>,
+    /// Size of the frames larger outgoing messages are split into, 0 never splits them
+    /// [default: 1 MiB].
+    #[arg(long)]
+    fragment_size: Option<usize>,
+    /// Longest request line of the opening handshake, in bytes [default: 8 KiB].
+    #[arg(long)]
+    max_request_line: Option<usize>,
+    /// Longest single header line of the opening handshake, in bytes [default: 8 KiB].
+    #[arg(long)]
+    max_header_line: Option<usize>,
+    /// Largest size of all handshake headers together, in bytes [default: 64 KiB].
+    #[arg(long)]
+    max_header_bytes: Option<usize>,
+    /// Most headers the opening handshake may carry [default: 100].
+    #[arg(long)]
+    max_headers: Option<usize>,
+    /// Seconds a client has to complete the opening handshake [default: 10].
+    #[arg(long)]
+    handshake_timeout: Option<f64>,
+    /// Seconds a client has to answer our Close frame [default: 5].
+    #[arg(long)]
+    close_timeout: Option<f64>,
+    /// Seconds connections get to close on shutdown [default: 10].
+    #[arg(long)]
+    drain_timeout: Option<f64>,
+    /// Seconds of silence before a client is pinged, 0 turns keepalive off [default: 30].
+    #[arg(long)]
+    keepalive_interval: Option<f64>,
+    /// Seconds a pinged client has to answer [default: 10].
+    #[arg(long)]
+    keepalive_timeout: Option<f64>,
+    /// PEM certificate chain, serves wss:// together with --tls-key.
+    #[arg(long, env = "RWS_TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+    #[arg(long, env = "RWS_TLS_KEY")]
+    tls_key: Option<PathBuf>,
+    /// PEM bundle of the CAs client certificates must chain to.
+    #[arg(long, env = "RWS_TLS_CLIENT_CA")]
+    tls_client_ca: Option<PathBuf>,
+    /// Whether clients must present a certificate signed by --tls-client-ca [default: required].
+    #[arg(long, value_enum, env = "RWS_TLS_CLIENT_AUTH")]
+    tls_client_auth: Option<ClientAuthMode>,
+}
+
+/// The config file, every setting is optional.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct File {
+    bind: Option<Vec<String>>,
+    handler: Option<Mode>,
+    log_level: Option<String>,
+    max_frame_size: Option<usize>,
+    max_message_size: Option<usize>,
+    max_fragments: Option<usize>,
+    fragment_size: Option<usize>,
+    max_request_line: Option<usize>,
+    max_header_line: Option<usize>,
+    max_header_bytes: Option<usize>,
+    max_headers: Option<usize>,
+    #[serde(default)]
+    timeouts: Timeouts,
+    #[serde(default)]
+    tls: Tls,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct Timeouts {
+    handshake: Option<f64>,
+    close: Option<f64>,
+    drain: Option<f64>,
+    keepalive_interval: Option<f64>,
+    keepalive_timeout: Option<f64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct Tls {
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    client_ca: Option<PathBuf>,
+    client_auth: Option<ClientAuthMode>,
+}
+
+/// Certificate files of a wss:// listener.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsPaths {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub client_ca: Option<PathBuf>,
+    pub client_auth: ClientAuthMode,
+}
+
+/// Validated settings the server runs with.
+#[derive(Clone)]
+pub struct Settings {
+    pub bind: Vec<SocketAddr>,
+    pub handler: Mode,
+    pub log_level: LevelFilter,
+    pub server: ServerConfig,
+    // Always None without the tls feature
+    #[cfg_attr(not(feature = "tls"), allow(dead_code))]
+    pub tls: Option<TlsPaths>,
+}
+
+impl Settings {
+    /// Reads the config file named in `args`, if any, and merges the two.
+    pub fn load(args: Args) -> Result<Self, ConfigError> {
+        let file = match &args.config {
+            Some(path) => {
+                let text =
+                    fs::read_to_string(path).map_err(|e| ConfigError::Read(path.clone(), e))?;
+                toml::from_str(&text).map_err(|e| ConfigError::Parse(path.clone(), e))?
+            }
+            None => File::default(),
+        };
+        Self::merge(args, file)
+    }
+
+    fn merge(args: Args, file: File) -> Result<Self, ConfigError> {
+        let bind = match (args.bind, file.bind) {
+            (bind, _) if !bind.is_empty() => bind,
+            (_, Some(bind)) => bind,
+            _ => vec![DEFAULT_BIND.to_string()],
+        };
+        if bind.is_empty() {
+            return Err(ConfigError::NoBindAddress);
+        }
+        let bind = bind
+            .into_iter()
+            .map(|addr| SocketAddr::from_str(&addr).map_err(|e| ConfigError::Bind(addr, e)))
+            .collect::<Result<_, _>>()?;
+
+        let log_level = args.log_level.or(file.log_level);
+        let log_level = match log_level {
+            Some(level) => {
+                LevelFilter::from_str(&level).map_err(|_| ConfigError::LogLevel(level))?
+            }
+            None => LevelFilter::Info,
+        };
+
+        let defaults = ServerConfig::default();
+        let max_message_size = limit(
+            "max_message_size",
+            args.max_message_size.or(file.max_message_size),
+            defaults.limits.max_message_size,
+        )?;
+        // Only a frame size that was asked for can conflict, the default shrinks to fit
+        let max_frame_size = match args.max_frame_size.or(file.max_frame_size) {
+            Some(0) => return Err(ConfigError::ZeroLimit("max_frame_size")),
+            Some(size) if size > max_message_size => {
+                return Err(ConfigError::FrameLargerThanMessage(size, max_message_size))
+            }
+            Some(size) => size,
+            None => defaults.limits.max_frame_size.min(max_message_size),
+        };
+        let limits = Limits {
+            max_frame_size,
+            max_message_size,
+            max_fragments: limit(
+                "max_fragments",
+                args.max_fragments.or(file.max_fragments),
+                defaults.limits.max_fragments,
+            )?,
+        };
+
+        let fragment_size = match args.fragment_size.or(file.fragment_size) {
+            Some(0) => None,
+            Some(size) => Some(size),
+            None => defaults.fragment_size,
+        };
+
+        let timeouts = file.timeouts;
+        let handshake_timeout =
+            positive("handshake", args.handshake_timeout.or(timeouts.handshake))?;
+        let close_timeout = positive("close", args.close_timeout.or(timeouts.close))?;
+        let drain_timeout = non_negative("drain", args.drain_timeout.or(timeouts.drain))?;
+        let interval = non_negative(
+            "keepalive_interval",
+            args.keepalive_interval.or(timeouts.keepalive_interval),
+        )?;
+        let keepalive_timeout = positive(
+            "keepalive_timeout",
+            args.keepalive_timeout.or(timeouts.keepalive_timeout),
+        )?;
+        let keepalive = match interval {
+            Some(Duration::ZERO) => None,
+            interval => {
+                let defaults = Keepalive::default();
+                Some(Keepalive {
+                    interval: interval.unwrap_or(defaults.interval),
+                    timeout: keepalive_timeout.unwrap_or(defaults.timeout),
+                })
+            }
+        };
+
+        let tls = file.tls;
+        let client_ca = args.tls_client_ca.or(tls.client_ca);
+        let client_auth = args.tls_client_auth.or(tls.client_auth);
+        if client_auth.is_some() && client_ca.is_none() {
+            return Err(ConfigError::ClientAuthWithoutCa);
+        }
+        let tls = match (args.tls_cert.or(tls.cert), args.tls_key.or(tls.key)) {
+            (Some(cert), Some(key)) => Some(TlsPaths {
+                cert,
+                key,
+                client_ca,
+                client_auth: client_auth.unwrap_or_default(),
+            }),
+            (None, None) if client_ca.is_none() => None,
+            _ => return Err(ConfigError::TlsIncomplete),
+        };
+        if tls.is_some() && !cfg!(feature = "tls") {
+            return Err(ConfigError::TlsUnsupported);
+        }
+
+        let handshake_defaults = defaults.handshake.limits;
+        let handshake_limits = HandshakeLimits {
+            max_request_line: limit(
+                "max_request_line",
+                args.max_request_line.or(file.max_request_line),
+                handshake_defaults.max_request_line,
+            )?,
+            max_header_line: limit(
+                "max_header_line",
+                args.max_header_line.or(file.max_header_line),
+                handshake_defaults.max_header_line,
+            )?,
+            max_header_bytes: limit(
+                "max_header_bytes",
+                args.max_header_bytes.or(file.max_header_bytes),
+                handshake_defaults.max_header_bytes,
+            )?,
+            max_headers: limit(
+                "max_headers",
+                args.max_headers.or(file.max_headers),
+                handshake_defaults.max_headers,
+            )?,
+            timeout: handshake_timeout.unwrap_or(handshake_defaults.timeout),
+        };
+
+        let mut server = ServerConfig {
+            limits,
+            fragment_size,
+            keepalive,
+            close_timeout: close_timeout.unwrap_or(defaults.close_timeout),
+            drain_timeout: drain_timeout.unwrap_or(defaults.drain_timeout),
+            ..defaults
+        };
+        server.handshake.limits = handshake_limits;
+
+        Ok(Self {
+            bind,
+            handler: args.handler.or(file.handler).unwrap_or_default(),
+            log_level,
+            server,
+            tls,
+        })
+    }
+}
+
+fn limit(name: &'static str, value: Option<usize>, default: usize) -> Result<usize, ConfigError> {
+    match value {
+        Some(0) => Err(ConfigError::ZeroLimit(name)),
+        value => Ok(value.unwrap_or(default)),
+    }
+}
+
+fn non_negative(name: &'static str, secs: Option<f64>) -> Result<Option<Duration>, ConfigError> {
+    secs.map(|secs| {
+        Duration::try_from_secs_f64(secs)
+            .map_err(|_| ConfigError::Timeout(name, secs, "a non-negative"))
+    })
+    .transpose()
+}
+
+fn positive(name: &'static str, secs: Option<f64>) -> Result<Option<Duration>, ConfigError> {
+    match non_negative(name, secs) {
+        Ok(Some(Duration::ZERO)) | Err(_) => Err(ConfigError::Timeout(
+            name,
+            secs.unwrap_or_default(),
+            "a positive",
+        )),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(flags: &[&str], file: &str) -> Result<Settings, ConfigError> {
+        let args =
+            Args::try_parse_from(std::iter::once("echo").chain(flags.iter().copied())).unwrap();
+        let file = toml::from_str(file).map_err(|e| ConfigError::Parse(PathBuf::new(), e))?;
+        Settings::merge(args, file)
+    }
+
+    #[test]
+    fn test_defaults() {
+        let settings = parse(&[], "").unwrap();
+        assert_eq!(settings.bind, vec![DEFAULT_BIND.parse().unwrap()]);
+        assert_eq!(settings.handler, Mode::Echo);
+        assert_eq!(settings.log_level, LevelFilter::Info);
+        assert_eq!(settings.server.limits, Limits::default());
+        assert_eq!(settings.server.keepalive, Some(Keepalive::default()));
+        assert_eq!(settings.tls, None);
+    }
+
+    #[test]
+    fn test_file_and_flags() {
+        let file = r#"
+            bind = ["0.0.0.0:9000", "[::1]:9000"]
+            handler = "discard"
+            log_level = "debug"
+            max_frame_size = 1024
+            max_message_size = 4096
+            fragment_size = 0
+            max_request_line = 2048
+            max_header_line = 1024
+            max_header_bytes = 16384
+            max_headers = 32
+
+            [timeouts]
+            handshake = 1.5
+            close = 2
+            drain = 0.5
+            keepalive_interval = 0
+        "#;
+        let settings = parse(
+            &[
+                "--max-message-size",
+                "2048",
+                "--close-timeout",
+                "3",
+                "--max-headers",
+                "16",
+            ],
+            file,
+        )
+        .unwrap();
+        assert_eq!(settings.bind.len(), 2);
+        assert_eq!(settings.handler, Mode::Discard);
+        assert_eq!(settings.log_level, LevelFilter::Debug);
+        assert_eq!(settings.server.limits.max_frame_size, 1024);
+        assert_eq!(settings.server.limits.max_message_size, 2048);
+        assert_eq!(settings.server.fragment_size, None);
+        assert_eq!(
+            settings.server.handshake.limits,
+            HandshakeLimits {
+                max_request_line: 2048,
+                max_header_line: 1024,
+                max_header_bytes: 16384,
+                max_headers: 16,
+                timeout: Duration::from_millis(1500),
+            }
+        );
+        assert_eq!(settings.server.close_timeout, Duration::from_secs(3));
+        assert_eq!(settings.server.drain_timeout, Duration::from_millis(500));
+        assert_eq!(settings.server.keepalive, None);
+
+        // A smaller message size alone lowers the default frame size with it
+        let settings = parse(&["--max-message-size", "1048576"], "").unwrap();
+        assert_eq!(settings.server.limits.max_frame_size, 1048576);
+        assert_eq!(settings.server.limits.max_message_size, 1048576);
+
+        let settings = parse(&["--bind", "127.0.0.1:1", "--fragment-size", "512"], file).unwrap();
+        assert_eq!(settings.bind, vec!["127.0.0.1:1".parse().unwrap()]);
+        assert_eq!(settings.server.fragment_size, Some(512));
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        assert!(matches!(
+            parse(&["--bind", "localhost"], ""),
+            Err(ConfigError::Bind(addr, _)) if addr == "localhost"
+        ));
+        assert!(matches!(
+            parse(&[], "bind = []"),
+            Err(ConfigError::NoBindAddress)
+        ));
+        assert!(matches!(
+            parse(&["--log-level", "loud"], ""),
+            Err(ConfigError::LogLevel(_))
+        ));
+        assert!(matches!(
+            parse(&["--max-fragments", "0"], ""),
+            Err(ConfigError::ZeroLimit("max_fragments"))
+        ));
+        assert!(matches!(
+            parse(&[], "max_header_line = 0"),
+            Err(ConfigError::ZeroLimit("max_header_line"))
+        ));
+        assert!(matches!(
+            parse(
+                &["--max-frame-size", "2048", "--max-message-size", "1024"],
+                ""
+            ),
+            Err(ConfigError::FrameLargerThanMessage(2048, 1024))
+        ));
+        assert!(matches!(
+            parse(&["--drain-timeout=-1"], ""),
+            Err(ConfigError::Timeout("drain", _, _))
+        ));
+        assert!(matches!(
+            parse(&[], "[timeouts]\nkeepalive_timeout = 0"),
+            Err(ConfigError::Timeout("keepalive_timeout", _, _))
+        ));
+        assert!(matches!(
+            parse(&[], "[tls]\ncert = \"cert.pem\""),
+            Err(ConfigError::TlsIncomplete)
+        ));
+        assert!(matches!(
+            parse(&["--tls-client-auth", "optional"], ""),
+            Err(ConfigError::ClientAuthWithoutCa)
+        ));
+        assert!(matches!(
+            parse(&[], "[tls]\nclient_auth = \"sometimes\""),
+            Err(ConfigError::Parse(_, _))
+        ));
+        // Typos are not silently ignored
+        assert!(matches!(
+            parse(&[], "max_payload = 1"),
+            Err(ConfigError::Parse(_, _))
+        ));
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_tls_client_auth() {
+        let file = r#"
+            [tls]
+            cert = "cert.pem"
+            key = "key.pem"
+            client_ca = "ca.pem"
+        "#;
+        let tls = parse(&[], file).unwrap().tls.unwrap();
+        assert_eq!(tls.client_ca, Some(PathBuf::from("ca.pem")));
+        assert_eq!(tls.client_auth, ClientAuthMode::Required);
+
+        let file = format!("{}client_auth = \"optional\"", file);
+        let tls = parse(&[], &file).unwrap().tls.unwrap();
+        assert_eq!(tls.client_auth, ClientAuthMode::Optional);
+        let tls = parse(&["--tls-client-auth", "required"], &file)
+            .unwrap()
+            .tls
+            .unwrap();
+        assert_eq!(tls.client_auth, ClientAuthMode::Required);
+    }
+
+    #[test]
+    fn test_missing_config_file() {
+        let args = Args::try_parse_from(["echo", "--config", "/nonexistent/rws.toml"]).unwrap();
+        assert!(matches!(Settings::load(args), Err(ConfigError::Read(_, _))));
+    }
+}
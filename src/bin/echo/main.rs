@@ -0,0 +1,144 @@
+mod config;
+
+use std::io;
+use std::process::ExitCode;
+use std::sync::Arc;
+
+use clap::Parser;
+use config::{Args, Mode, Settings};
+use rws::deflate::DeflateConfig;
+use rws::{run, shutdown_signal, Echo, Handler, Message, Shutdown, WsWriteHalf};
+use tokio::net::TcpListener;
+use tokio::task::JoinSet;
+
+/// Drops whatever it receives.
+struct Discard;
+
+impl Handler for Discard {
+    async fn on_message(&mut self, _: Message, _: &WsWriteHalf) {}
+}
+
+/// Writes log records to stderr.
+struct Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("[{}] {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let settings = match Settings::load(Args::parse()) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            return ExitCode::from(2);
+        }
+    };
+    static LOGGER: Logger = Logger;
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(settings.log_level);
+    }
+
+    let served = match settings.handler {
+        Mode::Echo => serve(settings, || Echo).await,
+        Mode::Discard => serve(settings, || Discard).await,
+    };
+    match served {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            log::error!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Listens on every bind address until SIGINT or SIGTERM.
+async fn serve<H: Handler>(
+    mut settings: Settings,
+    new_handler: impl Fn() -> H + Clone + Send + 'static,
+) -> io::Result<()> {
+    settings.server.handshake.extensions = vec![Arc::new(DeflateConfig::default())];
+
+    let mut listeners = Vec::new();
+    for addr in &settings.bind {
+        let listener = TcpListener::bind(addr)
+            .await
+            .map_err(|e| io::Error::new(e.kind(), format!("Failed to bind {}: {}", addr, e)))?;
+        log::info!("Listening on {}", addr);
+        listeners.push(listener);
+    }
+
+    // Serves wss:// when built with the tls feature and given a certificate
+    #[cfg(feature = "tls")]
+    let acceptor = match &settings.tls {
+        Some(paths) => {
+            let mut tls = rws::TlsConfig::new(&paths.cert, &paths.key);
+            if let Some(ca) = &paths.client_ca {
+                tls.client_auth = match paths.client_auth {
+                    config::ClientAuthMode::Optional => rws::ClientAuth::Optional(ca.clone()),
+                    config::ClientAuthMode::Required => rws::ClientAuth::Required(ca.clone()),
+                };
+            }
+            let acceptor = rws::ReloadableAcceptor::from(tls.acceptor().map_err(io::Error::other)?);
+            // Picks up rotated certificates on SIGHUP or when the files change
+            tokio::spawn(rws::reload_on_change(
+                tls,
+                acceptor.clone(),
+                std::time::Duration::from_secs(5),
+            ));
+            Some(acceptor)
+        }
+        None => None,
+    };
+
+    let shutdown = Shutdown::new();
+    let signal = shutdown.clone();
+    tokio::spawn(async move {
+        match shutdown_signal().await {
+            Ok(()) => {
+                log::info!("Shutting down");
+                signal.trigger();
+            }
+            Err(e) => log::error!("Failed to listen for shutdown signals, serving on: {}", e),
+        }
+    });
+
+    let mut servers = JoinSet::new();
+    for listener in listeners {
+        let config = settings.server.clone();
+        let new_handler = new_handler.clone();
+        let shutdown = shutdown.clone();
+        #[cfg(feature = "tls")]
+        if let Some(acceptor) = &acceptor {
+            servers.spawn(rws::run_tls(
+                listener,
+                acceptor.clone(),
+                config,
+                new_handler,
+                shutdown,
+            ));
+            continue;
+        }
+        servers.spawn(run(listener, config, new_handler, shutdown));
+    }
+
+    // One failing listener takes the others down with it
+    let mut result = Ok(());
+    while let Some(served) = servers.join_next().await {
+        if let Err(e) = served.map_err(io::Error::other).and_then(|served| served) {
+            shutdown.trigger();
+            result = result.and(Err(e));
+        }
+    }
+    result
+}
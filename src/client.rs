@@ -0,0 +1,179 @@
+use crate::frame::{FrameError, Opcode, Role};
+use crate::handshake::{do_client_handshake, HandshakeError};
+use crate::message::Message;
+use crate::reader::{Chunk, Limits, Reader};
+use crate::split::{split, WsReadHalf, WsWriteHalf};
+use crate::writer::{Writer, DEFAULT_FRAGMENT_SIZE};
+use tokio::io::{AsyncRead, BufReader, BufWriter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+/// Settings for [`Client::connect`].
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub protocols: Vec<String>,
+    pub limits: Limits,
+    /// Size of the frames larger messages are split into, `None` never splits them.
+    pub fragment_size: Option<usize>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            protocols: Vec::new(),
+            limits: Limits::default(),
+            fragment_size: Some(DEFAULT_FRAGMENT_SIZE),
+        }
+    }
+}
+
+/// A client connection over plain TCP.
+pub struct Client {
+    read_half: BufReader<OwnedReadHalf>,
+    write_half: BufWriter<OwnedWriteHalf>,
+    reader: Reader,
+    writer: Writer,
+    pub protocol: Option<String>,
+}
+
+impl Client {
+    /// Connects to `addr` and upgrades the connection for the resource at `path`.
+    pub async fn connect(
+        addr: &str,
+        path: &str,
+        config: &ClientConfig,
+    ) -> Result<Self, HandshakeError> {
+        let stream = TcpStream::connect(addr).await?;
+        let (read_half, write_half) = stream.into_split();
+        let mut read_half = BufReader::new(read_half);
+        let mut write_half = BufWriter::new(write_half);
+
+        let handshake = do_client_handshake(
+            &mut read_half,
+            &mut write_half,
+            addr,
+            path,
+            &config.protocols,
+        )
+        .await?;
+
+        let mut writer = Writer::new(Role::Client);
+        writer.set_fragment_size(config.fragment_size);
+        Ok(Self {
+            read_half,
+            write_half,
+            reader: Reader::with_limits(Role::Client, config.limits),
+            writer,
+            protocol: handshake.protocol,
+        })
+    }
+
+    pub fn set_mask_source(
+        &mut self,
+        mask_source: impl FnMut() -> [u8; 4] + Send + Sync + 'static,
+    ) {
+        self.writer.set_mask_source(mask_source);
+    }
+
+    pub async fn read(&mut self) -> Result<Message, FrameError> {
+        self.reader.read(&mut self.read_half).await
+    }
+
+    /// Reads the next piece of a message, see [`Reader::read_chunk`].
+    pub async fn read_chunk(&mut self) -> Result<Chunk, FrameError> {
+        self.reader.read_chunk(&mut self.read_half).await
+    }
+
+    pub async fn write(&mut self, message: Message) -> Result<(), FrameError> {
+        self.writer.write(message, &mut self.write_half).await
+    }
+
+    /// Streams `source` as one message, see [`Writer::write_from`].
+    pub async fn write_from(
+        &mut self,
+        opcode: Opcode,
+        source: &mut (impl AsyncRead + Unpin),
+        frame_size: usize,
+    ) -> Result<(), FrameError> {
+        self.writer
+            .write_from(opcode, source, frame_size, &mut self.write_half)
+            .await
+    }
+
+    /// Splits the connection so reading and writing can happen in different tasks.
+    pub fn split(self) -> (WsReadHalf<BufReader<OwnedReadHalf>>, WsWriteHalf) {
+        split(self.read_half, self.write_half, self.reader, self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::CloseCode;
+    use crate::handler::{serve, Echo, ServerConfig};
+    use crate::message::CloseFrame;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_client_echo() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve(stream, &ServerConfig::default(), Echo).await.unwrap();
+        });
+
+        let mut client = Client::connect(&addr, "/", &ClientConfig::default())
+            .await
+            .unwrap();
+        assert!(client.protocol.is_none());
+
+        client
+            .write(Message::Text("Hello".to_string()))
+            .await
+            .unwrap();
+        let message = client.read().await.unwrap();
+        assert_eq!(message, Message::Text("Hello".to_string()));
+
+        let close = Message::Close(Some(CloseFrame {
+            code: CloseCode::Normal,
+            reason: String::new(),
+        }));
+        client.write(close.clone()).await.unwrap();
+        assert_eq!(client.read().await.unwrap(), close);
+
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_split() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve(stream, &ServerConfig::default(), Echo).await.unwrap();
+        });
+
+        let client = Client::connect(&addr, "/", &ClientConfig::default())
+            .await
+            .unwrap();
+        let (mut incoming, outgoing) = client.split();
+        let sender = tokio::spawn(async move {
+            outgoing
+                .write(Message::Text("Hello".to_string()))
+                .await
+                .unwrap();
+            outgoing
+        });
+        assert_eq!(
+            incoming.read().await.unwrap(),
+            Message::Text("Hello".to_string())
+        );
+
+        let outgoing = sender.await.unwrap();
+        outgoing.write(Message::Close(None)).await.unwrap();
+        assert_eq!(incoming.read().await.unwrap(), Message::Close(None));
+
+        server.await.unwrap();
+    }
+}
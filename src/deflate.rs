@@ -0,0 +1,431 @@
+use std::fmt;
+
+use crate::extension::{Extension, ExtensionFactory, Negotiated};
+use crate::frame::{Frame, FrameError, Opcode, RSV1};
+use crate::handshake::ExtensionOffer;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+const EXTENSION_NAME: &str = "permessage-deflate";
+// Every sync flush ends with an empty stored block, which RFC 7692 strips from the wire
+const TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+const MAX_WINDOW_BITS: u8 = 15;
+// zlib cannot produce raw deflate streams with an 8 bit window
+const MIN_WINDOW_BITS: u8 = 9;
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Server settings for permessage-deflate (RFC 7692), used as an [`ExtensionFactory`].
+#[derive(Debug, Clone, Copy)]
+pub struct DeflateConfig {
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool,
+    pub server_max_window_bits: u8,
+    pub client_max_window_bits: u8,
+}
+
+impl Default for DeflateConfig {
+    fn default() -> Self {
+        Self {
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+            server_max_window_bits: MAX_WINDOW_BITS,
+            client_max_window_bits: MAX_WINDOW_BITS,
+        }
+    }
+}
+
+/// The parameters agreed on with a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeflateParams {
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool,
+    pub server_max_window_bits: u8,
+    pub client_max_window_bits: u8,
+}
+
+impl ExtensionFactory for DeflateConfig {
+    fn name(&self) -> &str {
+        EXTENSION_NAME
+    }
+
+    fn negotiate(&self, offer: &ExtensionOffer) -> Option<Negotiated> {
+        let params = self.accept(offer)?;
+        Some(Negotiated {
+            response: params.to_string(),
+            decoder: Box::new(params.inflater()),
+            encoder: Box::new(params.deflater()),
+        })
+    }
+}
+
+impl DeflateConfig {
+    fn accept(&self, offer: &ExtensionOffer) -> Option<DeflateParams> {
+        let mut params = DeflateParams {
+            server_no_context_takeover: self.server_no_context_takeover,
+            client_no_context_takeover: self.client_no_context_takeover,
+            server_max_window_bits: self
+                .server_max_window_bits
+                .clamp(MIN_WINDOW_BITS, MAX_WINDOW_BITS),
+            client_max_window_bits: MAX_WINDOW_BITS,
+        };
+
+        let mut seen = Vec::with_capacity(offer.params.len());
+        for (key, value) in &offer.params {
+            // Duplicate parameters make the whole offer invalid
+            if seen.contains(&key) {
+                return None;
+            }
+            seen.push(key);
+
+            match (key.as_str(), value) {
+                ("server_no_context_takeover", None) => params.server_no_context_takeover = true,
+                // A hint that the client can reset its context, we only ask for it when configured
+                ("client_no_context_takeover", None) => {}
+                ("server_max_window_bits", Some(value)) => {
+                    let bits = parse_window_bits(value)?;
+                    if bits < MIN_WINDOW_BITS {
+                        return None;
+                    }
+                    params.server_max_window_bits = params.server_max_window_bits.min(bits);
+                }
+                ("client_max_window_bits", None) => {
+                    params.client_max_window_bits = self.client_max_window_bits
+                }
+                ("client_max_window_bits", Some(value)) => {
+                    params.client_max_window_bits =
+                        self.client_max_window_bits.min(parse_window_bits(value)?)
+                }
+                _ => return None,
+            }
+        }
+
+        Some(params)
+    }
+}
+
+fn parse_window_bits(value: &str) -> Option<u8> {
+    value
+        .parse::<u8>()
+        .ok()
+        .filter(|bits| (8..=MAX_WINDOW_BITS).contains(bits))
+}
+
+impl DeflateParams {
+    pub fn deflater(&self) -> Deflater {
+        Deflater::new(self.server_no_context_takeover, self.server_max_window_bits)
+    }
+
+    pub fn inflater(&self) -> Inflater {
+        Inflater::new(self.client_no_context_takeover)
+    }
+}
+
+impl fmt::Display for DeflateParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(EXTENSION_NAME)?;
+        if self.server_no_context_takeover {
+            f.write_str("; server_no_context_takeover")?;
+        }
+        if self.client_no_context_takeover {
+            f.write_str("; client_no_context_takeover")?;
+        }
+        if self.server_max_window_bits < MAX_WINDOW_BITS {
+            write!(
+                f,
+                "; server_max_window_bits={}",
+                self.server_max_window_bits
+            )?;
+        }
+        if self.client_max_window_bits < MAX_WINDOW_BITS {
+            write!(
+                f,
+                "; client_max_window_bits={}",
+                self.client_max_window_bits
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Compresses outgoing messages.
+pub struct Deflater {
+    compress: Compress,
+    no_context_takeover: bool,
+}
+
+impl Deflater {
+    pub fn new(no_context_takeover: bool, window_bits: u8) -> Self {
+        let window_bits = window_bits.clamp(MIN_WINDOW_BITS, MAX_WINDOW_BITS);
+        Self {
+            compress: Compress::new_with_window_bits(Compression::default(), false, window_bits),
+            no_context_takeover,
+        }
+    }
+
+    pub fn compress(&mut self, data: &[u8]) -> Result<Vec<u8>, FrameError> {
+        let mut output = Vec::with_capacity(data.len() / 2 + TRAILER.len());
+        let start = self.compress.total_in();
+
+        loop {
+            let read = (self.compress.total_in() - start) as usize;
+            output.reserve(CHUNK_SIZE.min(data.len() - read + TRAILER.len()));
+            self.compress
+                .compress_vec(&data[read..], &mut output, FlushCompress::Sync)?;
+
+            // The flush is complete once all input is consumed and zlib stopped short of the buffer end
+            let read = (self.compress.total_in() - start) as usize;
+            if read == data.len() && output.len() < output.capacity() {
+                break;
+            }
+        }
+
+        if output.ends_with(&TRAILER) {
+            output.truncate(output.len() - TRAILER.len());
+        }
+        if self.no_context_takeover {
+            self.compress.reset();
+        }
+
+        Ok(output)
+    }
+}
+
+impl Extension for Deflater {
+    fn rsv_bits(&self) -> u8 {
+        RSV1
+    }
+
+    fn encode(&mut self, frame: Frame) -> Result<Frame, FrameError> {
+        // Only unfragmented data messages are compressed
+        if !frame.fin || !matches!(frame.opcode, Opcode::Text | Opcode::Binary) {
+            return Ok(frame);
+        }
+
+        let mut compressed = Frame::new(frame.opcode, self.compress(&frame.data)?);
+        compressed.rsv = frame.rsv | RSV1;
+        Ok(compressed)
+    }
+}
+
+/// Decompresses incoming messages.
+pub struct Inflater {
+    decompress: Decompress,
+    no_context_takeover: bool,
+}
+
+impl Inflater {
+    pub fn new(no_context_takeover: bool) -> Self {
+        // A full window can decode anything the peer produced with a smaller one
+        Self {
+            decompress: Decompress::new_with_window_bits(false, MAX_WINDOW_BITS),
+            no_context_takeover,
+        }
+    }
+
+    pub fn decompress(&mut self, data: &[u8], max_size: usize) -> Result<Vec<u8>, FrameError> {
+        let mut output = Vec::with_capacity((data.len() * 2).min(max_size));
+        let mut finished = self.inflate(data, &mut output, max_size)?;
+        if !finished {
+            finished = self.inflate(&TRAILER, &mut output, max_size)?;
+        }
+
+        if finished || self.no_context_takeover {
+            self.decompress.reset(false);
+        }
+
+        Ok(output)
+    }
+
+    fn inflate(
+        &mut self,
+        input: &[u8],
+        output: &mut Vec<u8>,
+        max_size: usize,
+    ) -> Result<bool, FrameError> {
+        let start = self.decompress.total_in();
+
+        loop {
+            if output.len() == output.capacity() {
+                output.reserve(CHUNK_SIZE);
+            }
+
+            let read = (self.decompress.total_in() - start) as usize;
+            let status =
+                self.decompress
+                    .decompress_vec(&input[read..], output, FlushDecompress::Sync)?;
+
+            if output.len() > max_size {
+                return Err(FrameError::MessageTooLarge);
+            }
+            if status == Status::StreamEnd {
+                return Ok(true);
+            }
+
+            let read = (self.decompress.total_in() - start) as usize;
+            if read == input.len() && output.len() < output.capacity() {
+                return Ok(false);
+            }
+        }
+    }
+}
+
+impl Extension for Inflater {
+    fn rsv_bits(&self) -> u8 {
+        RSV1
+    }
+
+    fn decode(&mut self, frame: Frame, max_size: usize) -> Result<Frame, FrameError> {
+        if frame.rsv & RSV1 == 0 {
+            return Ok(frame);
+        }
+
+        let mut decompressed = Frame::new(frame.opcode, self.decompress(&frame.data, max_size)?);
+        decompressed.rsv = frame.rsv & !RSV1;
+        Ok(decompressed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offer(params: &[(&str, Option<&str>)]) -> ExtensionOffer {
+        ExtensionOffer {
+            name: EXTENSION_NAME.to_string(),
+            params: params
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.map(str::to_string)))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_negotiate_defaults() {
+        let params = DeflateConfig::default()
+            .accept(&offer(&[("client_max_window_bits", None)]))
+            .unwrap();
+        assert_eq!(params.server_max_window_bits, 15);
+        assert_eq!(params.client_max_window_bits, 15);
+        assert_eq!(params.to_string(), "permessage-deflate");
+    }
+
+    #[test]
+    fn test_negotiate_offer_params() {
+        let config = DeflateConfig {
+            client_max_window_bits: 12,
+            ..DeflateConfig::default()
+        };
+        let params = config
+            .accept(&offer(&[
+                ("server_no_context_takeover", None),
+                ("server_max_window_bits", Some("10")),
+                ("client_max_window_bits", None),
+            ]))
+            .unwrap();
+        assert_eq!(
+            params.to_string(),
+            "permessage-deflate; server_no_context_takeover; server_max_window_bits=10; client_max_window_bits=12"
+        );
+    }
+
+    #[test]
+    fn test_negotiate_rejects_invalid_offers() {
+        let config = DeflateConfig::default();
+        assert!(config.accept(&offer(&[("unknown", None)])).is_none());
+        assert!(config
+            .accept(&offer(&[("server_max_window_bits", Some("16"))]))
+            .is_none());
+        assert!(config
+            .accept(&offer(&[("server_max_window_bits", Some("8"))]))
+            .is_none());
+        assert!(config
+            .accept(&offer(&[("server_max_window_bits", None)]))
+            .is_none());
+        assert!(config
+            .accept(&offer(&[
+                ("server_no_context_takeover", None),
+                ("server_no_context_takeover", None)
+            ]))
+            .is_none());
+    }
+
+    #[test]
+    fn test_negotiated_extension() {
+        let negotiated = DeflateConfig::default().negotiate(&offer(&[])).unwrap();
+        assert_eq!(negotiated.response, "permessage-deflate");
+        assert_eq!(negotiated.decoder.rsv_bits(), RSV1);
+        assert_eq!(negotiated.encoder.rsv_bits(), RSV1);
+    }
+
+    #[test]
+    fn test_decompress_rfc_example() {
+        // RFC 7692 section 7.2.3.1
+        let mut inflater = Inflater::new(false);
+        let data = inflater
+            .decompress(&[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00], 1024)
+            .unwrap();
+        assert_eq!(data, b"Hello");
+    }
+
+    #[test]
+    fn test_round_trip_with_context_takeover() {
+        let mut deflater = Deflater::new(false, 15);
+        let mut inflater = Inflater::new(false);
+
+        let first = deflater.compress(b"Hello Hello Hello").unwrap();
+        let second = deflater.compress(b"Hello Hello Hello").unwrap();
+        assert!(second.len() < first.len());
+
+        assert_eq!(
+            inflater.decompress(&first, 1024).unwrap(),
+            b"Hello Hello Hello"
+        );
+        assert_eq!(
+            inflater.decompress(&second, 1024).unwrap(),
+            b"Hello Hello Hello"
+        );
+    }
+
+    #[test]
+    fn test_round_trip_without_context_takeover() {
+        let mut deflater = Deflater::new(true, 9);
+        let mut inflater = Inflater::new(true);
+
+        let first = deflater.compress(b"Hello").unwrap();
+        let second = deflater.compress(b"Hello").unwrap();
+        assert_eq!(first, second);
+        assert_eq!(inflater.decompress(&second, 1024).unwrap(), b"Hello");
+    }
+
+    #[test]
+    fn test_decompress_too_large() {
+        let mut deflater = Deflater::new(false, 15);
+        let mut inflater = Inflater::new(false);
+
+        let data = deflater.compress(&vec![0; 64 * 1024]).unwrap();
+        assert!(matches!(
+            inflater.decompress(&data, 1024),
+            Err(FrameError::MessageTooLarge)
+        ));
+    }
+
+    #[test]
+    fn test_encode_decode_frames() {
+        let mut deflater = Deflater::new(false, 15);
+        let mut inflater = Inflater::new(false);
+
+        let encoded = deflater
+            .encode(Frame::new(Opcode::Text, b"Hello".to_vec()))
+            .unwrap();
+        assert_eq!(encoded.rsv, RSV1);
+
+        let decoded = inflater.decode(encoded, 1024).unwrap();
+        assert_eq!(decoded.rsv, 0);
+        assert_eq!(decoded.data, b"Hello");
+
+        let ping = deflater
+            .encode(Frame::new(Opcode::Ping, b"ping".to_vec()))
+            .unwrap();
+        assert_eq!(ping.rsv, 0);
+        assert_eq!(ping.data, b"ping");
+    }
+}
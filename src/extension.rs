@@ -0,0 +1,42 @@
+use crate::frame::{Frame, FrameError};
+use crate::handshake::ExtensionOffer;
+
+/// Server side of an extension, consulted during the opening handshake.
+pub trait ExtensionFactory: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Accepts or declines a single offer from the client's Sec-WebSocket-Extensions header.
+    fn negotiate(&self, offer: &ExtensionOffer) -> Option<Negotiated>;
+}
+
+/// An accepted extension, split into the state used on the read and write paths.
+pub struct Negotiated {
+    /// The element echoed back in the Sec-WebSocket-Extensions response header.
+    pub response: String,
+    pub decoder: Box<dyn Extension>,
+    pub encoder: Box<dyn Extension>,
+}
+
+/// Transforms whole messages on the read or write path.
+///
+/// RSV bits claimed by an extension are only accepted on the first frame of a
+/// message and on reserved opcodes, the control frames of RFC 6455 never carry them.
+pub trait Extension: Send + Sync {
+    /// RSV bits the extension may set, as a mask of `RSV1`, `RSV2` and `RSV3`.
+    fn rsv_bits(&self) -> u8 {
+        0
+    }
+
+    /// Reserved opcodes (0x3-0x7, 0xB-0xF) the extension gives meaning to.
+    fn opcodes(&self) -> &[u8] {
+        &[]
+    }
+
+    fn decode(&mut self, frame: Frame, _max_size: usize) -> Result<Frame, FrameError> {
+        Ok(frame)
+    }
+
+    fn encode(&mut self, frame: Frame) -> Result<Frame, FrameError> {
+        Ok(frame)
+    }
+}
@@ -1,14 +1,28 @@
 use std::io;
 use thiserror::Error;
 
+pub const RSV1: u8 = 0b0100_0000;
+pub const RSV2: u8 = 0b0010_0000;
+pub const RSV3: u8 = 0b0001_0000;
+
+/// Which end of the connection we are, clients mask every frame they send.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Role {
+    Server,
+    Client,
+}
+
+/// The frame opcodes of RFC 6455 section 5.2.
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum Opcode {
-    Continuation = 0x0, // 0000
-    Text = 0x1,         // 0001
-    Binary = 0x2,       // 0010
-    Close = 0x8,        // 1000
-    Ping = 0x9,         // 1001
-    Pong = 0xa,         // 1010
+    Continuation, // 0000
+    Text,         // 0001
+    Binary,       // 0010
+    Close,        // 1000
+    Ping,         // 1001
+    Pong,         // 1010
+    // 0011-0111 and 1011-1111, only valid when claimed by an extension
+    Reserved(u8),
 }
 
 impl TryFrom<u8> for Opcode {
@@ -22,25 +36,43 @@ impl TryFrom<u8> for Opcode {
             0x8 => Opcode::Close,
             0x9 => Opcode::Ping,
             0xa => Opcode::Pong,
+            0x3..=0x7 | 0xb..=0xf => Opcode::Reserved(value),
             _ => return Err(FrameError::InvalidOpCode(value)),
         })
     }
 }
 
+impl From<Opcode> for u8 {
+    fn from(opcode: Opcode) -> u8 {
+        match opcode {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xa,
+            Opcode::Reserved(value) => value,
+        }
+    }
+}
+
 impl Opcode {
     pub fn is_control(&self) -> bool {
-        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+        u8::from(*self) & 0b1000 != 0
     }
 }
 
-#[derive(Debug)]
+/// A single frame, or a complete message once reassembled by the [`Reader`](crate::Reader).
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Frame {
     pub fin: bool,
+    pub rsv: u8,
     pub opcode: Opcode,
     pub len: usize,
     pub data: Vec<u8>,
 }
 
+/// Everything that can go wrong while reading or writing frames.
 #[derive(Error, Debug)]
 pub enum FrameError {
     #[error("Invalid UTF-8")]
@@ -57,16 +89,57 @@ pub enum FrameError {
     InvalidPayloadLength(u64),
     #[error("Frame too large")]
     FrameTooLarge,
-    #[error("Ping Frame too large")]
-    PingFrameTooLarge,
+    #[error("Message too large")]
+    MessageTooLarge,
+    #[error("Too many fragments")]
+    TooManyFragments,
+    #[error("Control frame too large")]
+    ControlFrameTooLarge,
+    #[error("Unmasked frame from client")]
+    UnmaskedFrame,
+    #[error("Masked frame from server")]
+    MaskedFrame,
     #[error("Reserved bits are not zero")]
     ReservedBitsNotZero,
     #[error("Invalid fragment")]
     InvalidFragment,
     #[error("Invalid close frame")]
     InvalidCloseFrame,
+    #[error("Connection closed")]
+    ConnectionClosed,
+    #[error("Compression error: {0}")]
+    Compress(#[from] flate2::CompressError),
+    #[error("Decompression error: {0}")]
+    Decompress(#[from] flate2::DecompressError),
 }
 
+impl FrameError {
+    /// The status to close the connection with, `None` when the connection itself failed.
+    pub fn close_code(&self) -> Option<CloseCode> {
+        match self {
+            FrameError::Io(_) | FrameError::ConnectionClosed => None,
+            FrameError::InvalidUTF8 => Some(CloseCode::Invalid),
+            FrameError::FrameTooLarge
+            | FrameError::MessageTooLarge
+            | FrameError::TooManyFragments => Some(CloseCode::Size),
+            FrameError::Compress(_) => Some(CloseCode::Error),
+            FrameError::InvalidOpCode(_)
+            | FrameError::InvalidContinuation(_)
+            | FrameError::InvalidControlFin(_)
+            | FrameError::InvalidPayloadLength(_)
+            | FrameError::ControlFrameTooLarge
+            | FrameError::UnmaskedFrame
+            | FrameError::MaskedFrame
+            | FrameError::ReservedBitsNotZero
+            | FrameError::InvalidFragment
+            | FrameError::InvalidCloseFrame
+            | FrameError::Decompress(_) => Some(CloseCode::Protocol),
+        }
+    }
+}
+
+/// Status codes carried by Close frames, see RFC 6455 section 7.4.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum CloseCode {
     Normal,
     Away,
@@ -89,8 +162,15 @@ pub enum CloseCode {
 
 impl CloseCode {
     pub fn is_allowed(self) -> bool {
-        !matches!(self, CloseCode::Bad(_) | CloseCode::Reserved(_) | CloseCode::Status | CloseCode::Abnormal | CloseCode::Tls)
-      }
+        !matches!(
+            self,
+            CloseCode::Bad(_)
+                | CloseCode::Reserved(_)
+                | CloseCode::Status
+                | CloseCode::Abnormal
+                | CloseCode::Tls
+        )
+    }
 }
 
 impl From<u16> for CloseCode {
@@ -149,6 +229,7 @@ impl Frame {
     pub fn new(opcode: Opcode, data: Vec<u8>) -> Self {
         Self {
             fin: true,
+            rsv: 0,
             opcode,
             len: data.len(),
             data,
@@ -167,7 +248,7 @@ impl Frame {
                 }
 
                 // If there's more data, it must be valid UTF-8
-                if data.len() > 2 && !simdutf8::basic::from_utf8(&data[2..]).is_ok() {
+                if data.len() > 2 && simdutf8::basic::from_utf8(&data[2..]).is_err() {
                     return Err(FrameError::InvalidUTF8);
                 }
 
@@ -181,6 +262,13 @@ impl Frame {
         payload.extend_from_slice(&code.to_be_bytes());
         payload.extend_from_slice(reason);
 
-        return Self { fin: true, opcode: Opcode::Close, len: payload.len(), data: payload }
+        Self::new(Opcode::Close, payload)
+    }
+}
+
+/// XORs `data` with the 4 byte masking key, masking and unmasking are the same operation.
+pub fn apply_mask(data: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
     }
 }
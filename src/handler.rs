@@ -1,69 +1,561 @@
-use crate::frame::Frame;
-use crate::frame::Opcode;
-use crate::handshake::do_handshake;
-use crate::reader::Reader;
-use crate::writer::Writer;
-use tokio::io::{BufReader, BufWriter};
-use tokio::net::TcpStream;
-
-pub struct Handler {}
-
-impl Handler {
-    pub async fn handle_connection(stream: &mut TcpStream) {
-        let (read_half, write_half) = stream.split();
-        let mut read_half = BufReader::new(read_half);
-        let mut write_half = BufWriter::new(write_half);
-
-        match do_handshake(&mut read_half, &mut write_half).await {
-            Ok(_) => {
-                println!("Handshake successful");
-            }
-            Err(e) => {
-                println!("Handshake failed: {}", e);
-                return;
-            }
+use std::future::Future;
+use std::time::Duration;
+
+use crate::frame::{CloseCode, FrameError, Role};
+use crate::handshake::{do_handshake_until, HandshakeConfig, HandshakeError, Peer, Request};
+use crate::keepalive::{Keepalive, Tick, Timer};
+use crate::message::{CloseFrame, Message};
+use crate::reader::{Limits, Reader};
+use crate::split::{split, WsWriteHalf};
+use crate::writer::{Writer, DEFAULT_FRAGMENT_SIZE};
+use tokio::io::{AsyncRead, AsyncWrite, BufReader, BufWriter};
+use tokio::time::{sleep_until, timeout, timeout_at, Instant};
+
+/// Settings for the connections driven by [`serve`].
+#[derive(Clone)]
+pub struct ServerConfig {
+    pub handshake: HandshakeConfig,
+    /// What the peer may send, in frame size, message size and fragments per message.
+    pub limits: Limits,
+    /// Size of the frames larger messages are split into, so pongs and Close replies do not
+    /// wait for the whole message. `None` never splits them.
+    pub fragment_size: Option<usize>,
+    /// Pings quiet peers and drops those that stop answering, off by default.
+    pub keepalive: Option<Keepalive>,
+    /// How long the peer has to answer our Close frame before the connection is dropped.
+    pub close_timeout: Duration,
+    /// How long a shutting down server waits for its connections to close.
+    pub drain_timeout: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            handshake: HandshakeConfig::default(),
+            limits: Limits::default(),
+            fragment_size: Some(DEFAULT_FRAGMENT_SIZE),
+            keepalive: None,
+            close_timeout: Duration::from_secs(5),
+            drain_timeout: Duration::from_secs(10),
         }
+    }
+}
 
-        let mut reader = Reader::new(64 * 1024 * 1024);
+/// Application callbacks for a single connection, one value is created per connection.
+///
+/// Pongs and Close replies are sent by the connection itself. `sender` can be cloned to
+/// write from other tasks, [`WsWriteHalf::close`] starts the closing handshake.
+pub trait Handler: Send + 'static {
+    /// Called once the opening handshake succeeded, before any message is read.
+    fn on_open(
+        &mut self,
+        _request: &Request,
+        _protocol: Option<&str>,
+        _sender: &WsWriteHalf,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
 
-        loop {
-            let frame = match reader.read(&mut read_half).await {
-                Ok(frame) => frame,
-                Err(e) => {
-                    break;
-                }
-            };
+    /// Called for every message except Close.
+    fn on_message(
+        &mut self,
+        message: Message,
+        sender: &WsWriteHalf,
+    ) -> impl Future<Output = ()> + Send;
 
-            match frame.opcode {
-                Opcode::Text => {
-                    if let Err(e) = Writer::write_frame(&frame, &mut write_half).await {
-                        break;
-                    }
-                }
-                Opcode::Close => {
-                    if let Ok(reply) = Frame::new_close_reply(frame.data) {
-                        let _ = Writer::write_frame(&reply, &mut write_half).await;
-                    }
-                    break;
+    /// Called with the peer's Close frame, or 1006 when it stopped answering keepalive pings
+    /// or our Close frame. The connection ends afterwards.
+    fn on_close(&mut self, _close: Option<CloseFrame>) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// Called when reading fails, the connection ends afterwards.
+    fn on_error(&mut self, _error: FrameError) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+}
+
+/// Sends every text and binary message back to the peer.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Echo;
+
+impl Handler for Echo {
+    async fn on_message(&mut self, message: Message, sender: &WsWriteHalf) {
+        if let Message::Text(_) | Message::Binary(_) = message {
+            // A broken connection shows up on the next read
+            let _ = sender.write(message).await;
+        }
+    }
+}
+
+/// Performs the server handshake on `stream` and feeds the connection to `handler`.
+pub async fn serve<S, H>(stream: S, config: &ServerConfig, handler: H) -> Result<(), HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    H: Handler,
+{
+    serve_until(
+        stream,
+        Peer::default(),
+        config,
+        handler,
+        std::future::pending(),
+    )
+    .await
+}
+
+/// Like [`serve`], but closes the connection with 1001 Going Away once `shutdown` completes.
+/// `peer` is handed to the handler as part of the [`Request`].
+pub async fn serve_until<S, H>(
+    stream: S,
+    peer: Peer,
+    config: &ServerConfig,
+    handler: H,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    H: Handler,
+{
+    let deadline = Instant::now() + config.handshake.limits.timeout;
+    serve_with_deadline(stream, peer, config, handler, shutdown, deadline).await
+}
+
+/// Like [`serve_until`], with the opening handshake due by `deadline`.
+pub(crate) async fn serve_with_deadline<S, H>(
+    stream: S,
+    peer: Peer,
+    config: &ServerConfig,
+    mut handler: H,
+    shutdown: impl Future<Output = ()>,
+    deadline: Instant,
+) -> Result<(), HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    H: Handler,
+{
+    tokio::pin!(shutdown);
+    let mut shutting_down = false;
+
+    let (read_half, write_half) = tokio::io::split(stream);
+    let mut read_half = BufReader::new(read_half);
+    let mut write_half = BufWriter::new(write_half);
+    let mut handshake =
+        do_handshake_until(&mut read_half, &mut write_half, &config.handshake, deadline).await?;
+    handshake.request.peer = peer;
+
+    let mut reader = Reader::with_limits(Role::Server, config.limits);
+    let mut writer = Writer::new(Role::Server);
+    writer.set_fragment_size(config.fragment_size);
+    for extension in handshake.extensions {
+        reader.add_extension(extension.decoder);
+        writer.add_extension(extension.encoder);
+    }
+    let (mut incoming, outgoing) = split(read_half, write_half, reader, writer);
+
+    handler
+        .on_open(&handshake.request, handshake.protocol.as_deref(), &outgoing)
+        .await;
+    let mut timer = config.keepalive.map(Timer::new);
+    let mut state = incoming.watch_state();
+    // Set once our Close frame went out, the peer's has to arrive before it
+    let mut close_deadline = None;
+    loop {
+        let read = incoming.read();
+        tokio::pin!(read);
+        // Pings go out while the read is pending, it is only abandoned on timeouts
+        let event = loop {
+            if close_deadline.is_none() && state.borrow_and_update().is_close_sent() {
+                close_deadline = Some(Instant::now() + config.close_timeout);
+            }
+            let ping_deadline = timer
+                .as_ref()
+                .filter(|_| close_deadline.is_none())
+                .map(Timer::deadline);
+
+            tokio::select! {
+                result = &mut read => break Event::Read(result),
+                _ = state.changed(), if close_deadline.is_none() => {}
+                _ = &mut shutdown, if !shutting_down => {
+                    shutting_down = true;
+                    let away = outgoing.close(CloseCode::Away, "Server shutting down");
+                    let _ = timeout(config.close_timeout, away).await;
                 }
-                Opcode::Ping => {
-                    let pong_frame = Frame::new(Opcode::Pong, frame.data);
-                    if let Err(e) = Writer::write_frame(&pong_frame, &mut write_half).await {
-                        break;
+                _ = sleep_until_some(close_deadline) => break Event::CloseTimeout,
+                _ = sleep_until_some(ping_deadline) => {
+                    let Some(timer) = &mut timer else { continue };
+                    match timer.expired() {
+                        Tick::Ping(payload) => {
+                            let ping = outgoing.write(Message::Ping(payload));
+                            let _ = timeout_at(timer.deadline(), ping).await;
+                        }
+                        Tick::TimedOut => break Event::KeepaliveTimeout,
                     }
                 }
-                Opcode::Pong => {}
-                Opcode::Binary => {
-                    if let Err(e) = Writer::write_frame(&frame, &mut write_half).await {
-                        break;
-                    }
+            }
+        };
+
+        match event {
+            Event::Read(Ok(Message::Close(close))) => {
+                handler.on_close(close).await;
+                break;
+            }
+            Event::Read(Ok(message)) => {
+                if let Some(timer) = &mut timer {
+                    timer.received(&message);
                 }
-                Opcode::Continuation => {
-                    if let Err(e) = Writer::write_frame(&frame, &mut write_half).await {
-                        break;
-                    }
+                handler.on_message(message, &outgoing).await;
+            }
+            Event::Read(Err(e)) => {
+                handler.on_error(e).await;
+                break;
+            }
+            Event::KeepaliveTimeout | Event::CloseTimeout => {
+                if let Event::KeepaliveTimeout = event {
+                    // The peer is told we are going away, it is not waited for
+                    let away = outgoing.close(CloseCode::Away, "Keepalive timeout");
+                    let _ = timeout(config.close_timeout, away).await;
                 }
+                // Locally the connection closed abnormally
+                handler
+                    .on_close(Some(CloseFrame {
+                        code: CloseCode::Abnormal,
+                        reason: String::new(),
+                    }))
+                    .await;
+                break;
+            }
+        }
+    }
+
+    // The server closes the TCP connection first, RFC 6455 section 7.1.1
+    let _ = timeout(config.close_timeout, outgoing.shutdown()).await;
+
+    Ok(())
+}
+
+enum Event {
+    Read(Result<Message, FrameError>),
+    KeepaliveTimeout,
+    CloseTimeout,
+}
+
+async fn sleep_until_some(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{Frame, Opcode};
+    use crate::handshake::do_client_handshake;
+    use crate::keepalive::Keepalive;
+    use crate::reader::DEFAULT_MAX_MESSAGE_SIZE;
+    use tokio::io::{duplex, DuplexStream, ReadHalf, WriteHalf};
+    use tokio::sync::mpsc;
+
+    /// Reports every callback to the test.
+    struct Recorder {
+        events: mpsc::UnboundedSender<String>,
+        count: usize,
+    }
+
+    impl Handler for Recorder {
+        async fn on_open(&mut self, request: &Request, _: Option<&str>, _: &WsWriteHalf) {
+            let _ = self.events.send(format!("open {}", request.path));
+        }
+
+        async fn on_message(&mut self, message: Message, sender: &WsWriteHalf) {
+            if message == Message::Text("bye".to_string()) {
+                sender.close(CloseCode::Normal, "bye").await.unwrap();
+                let late = sender.write(Message::Text("late".to_string())).await;
+                let _ = self.events.send(format!("late {}", late.unwrap_err()));
+                return;
             }
+            self.count += 1;
+            let _ = sender.write(Message::Text(self.count.to_string())).await;
+            let _ = self.events.send(format!("message {:?}", message));
         }
+
+        async fn on_close(&mut self, close: Option<CloseFrame>) {
+            let _ = self
+                .events
+                .send(format!("close {:?}", close.map(|close| close.code)));
+        }
+
+        async fn on_error(&mut self, error: FrameError) {
+            let _ = self.events.send(format!("error {}", error));
+        }
+    }
+
+    struct TestClient {
+        read_half: BufReader<ReadHalf<DuplexStream>>,
+        write_half: WriteHalf<DuplexStream>,
+        reader: Reader,
+        writer: Writer,
+    }
+
+    impl TestClient {
+        async fn connect<H: Handler>(handler: H) -> Self {
+            Self::connect_with(handler, ServerConfig::default()).await
+        }
+
+        async fn connect_with<H: Handler>(handler: H, config: ServerConfig) -> Self {
+            let (server, client) = duplex(4096);
+            tokio::spawn(async move {
+                serve(server, &config, handler).await.unwrap();
+            });
+
+            let (read_half, mut write_half) = tokio::io::split(client);
+            let mut read_half = BufReader::new(read_half);
+            do_client_handshake(&mut read_half, &mut write_half, "localhost", "/chat", &[])
+                .await
+                .unwrap();
+            Self {
+                read_half,
+                write_half,
+                reader: Reader::new(Role::Client, DEFAULT_MAX_MESSAGE_SIZE),
+                writer: Writer::new(Role::Client),
+            }
+        }
+
+        async fn send(&mut self, message: Message) {
+            self.writer
+                .write(message, &mut self.write_half)
+                .await
+                .unwrap();
+        }
+
+        async fn receive(&mut self) -> Message {
+            self.reader.read(&mut self.read_half).await.unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_echo() {
+        let mut client = TestClient::connect(Echo).await;
+
+        client.send(Message::Text("Hello".to_string())).await;
+        assert_eq!(client.receive().await, Message::Text("Hello".to_string()));
+        client.send(Message::Binary(vec![1, 2])).await;
+        assert_eq!(client.receive().await, Message::Binary(vec![1, 2]));
+        client.send(Message::Ping(b"ping".to_vec())).await;
+        assert_eq!(client.receive().await, Message::Pong(b"ping".to_vec()));
+
+        client.send(Message::Close(None)).await;
+        assert_eq!(client.receive().await, Message::Close(None));
+    }
+
+    #[tokio::test]
+    async fn test_handler_callbacks() {
+        let (events, mut received) = mpsc::unbounded_channel();
+        let mut client = TestClient::connect(Recorder { events, count: 0 }).await;
+        assert_eq!(received.recv().await.unwrap(), "open /chat");
+
+        client.send(Message::Text("a".to_string())).await;
+        assert_eq!(client.receive().await, Message::Text("1".to_string()));
+        client.send(Message::Binary(vec![0])).await;
+        assert_eq!(client.receive().await, Message::Text("2".to_string()));
+        assert_eq!(received.recv().await.unwrap(), "message Text(\"a\")");
+        assert_eq!(received.recv().await.unwrap(), "message Binary([0])");
+
+        client
+            .send(Message::Close(Some(CloseFrame {
+                code: CloseCode::Away,
+                reason: String::new(),
+            })))
+            .await;
+        assert_eq!(received.recv().await.unwrap(), "close Some(Away)");
+        // The handler is dropped with the connection
+        assert!(received.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_handler_error() {
+        let (events, mut received) = mpsc::unbounded_channel();
+        let mut client = TestClient::connect(Recorder { events, count: 0 }).await;
+        assert_eq!(received.recv().await.unwrap(), "open /chat");
+
+        // Servers only accept masked frames
+        let frame = Frame::new(Opcode::Text, b"x".to_vec());
+        Writer::write_frame(&frame, &mut client.write_half)
+            .await
+            .unwrap();
+        assert_eq!(
+            received.recv().await.unwrap(),
+            "error Unmasked frame from client"
+        );
+        assert_eq!(
+            client.receive().await,
+            Message::Close(Some(CloseFrame {
+                code: CloseCode::Protocol,
+                reason: "Unmasked frame from client".to_string(),
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invalid_utf8_close() {
+        let mut client = TestClient::connect(Echo).await;
+
+        let frame = Frame::new(Opcode::Text, vec![0xff, 0xfe]);
+        client
+            .writer
+            .send_frame(frame, &mut client.write_half)
+            .await
+            .unwrap();
+        assert_eq!(
+            client.receive().await,
+            Message::Close(Some(CloseFrame {
+                code: CloseCode::Invalid,
+                reason: "Invalid UTF-8".to_string(),
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_message_size_limit() {
+        let config = ServerConfig {
+            limits: Limits {
+                max_frame_size: 4,
+                max_message_size: 5,
+                ..Limits::default()
+            },
+            ..ServerConfig::default()
+        };
+        let mut client = TestClient::connect_with(Echo, config).await;
+
+        client.send(Message::Text("tiny".to_string())).await;
+        assert_eq!(client.receive().await, Message::Text("tiny".to_string()));
+
+        // Every frame fits, the message they add up to does not
+        for (fin, opcode, data) in [
+            (false, Opcode::Text, "too"),
+            (false, Opcode::Continuation, "big"),
+        ] {
+            let frame = Frame {
+                fin,
+                ..Frame::new(opcode, data.as_bytes().to_vec())
+            };
+            client
+                .writer
+                .send_frame(frame, &mut client.write_half)
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            client.receive().await,
+            Message::Close(Some(CloseFrame {
+                code: CloseCode::Size,
+                reason: "Message too large".to_string(),
+            }))
+        );
+    }
+
+    fn keepalive_config() -> ServerConfig {
+        ServerConfig {
+            keepalive: Some(Keepalive {
+                interval: Duration::from_secs(10),
+                timeout: Duration::from_secs(5),
+            }),
+            ..ServerConfig::default()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keepalive_timeout() {
+        let (events, mut received) = mpsc::unbounded_channel();
+        let recorder = Recorder { events, count: 0 };
+        let mut client = TestClient::connect_with(recorder, keepalive_config()).await;
+        assert_eq!(received.recv().await.unwrap(), "open /chat");
+
+        let start = Instant::now();
+        assert!(matches!(client.receive().await, Message::Ping(_)));
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+
+        // Never answering the ping gets the connection dropped
+        assert_eq!(
+            client.receive().await,
+            Message::Close(Some(CloseFrame {
+                code: CloseCode::Away,
+                reason: "Keepalive timeout".to_string(),
+            }))
+        );
+        assert_eq!(start.elapsed(), Duration::from_secs(15));
+        assert_eq!(received.recv().await.unwrap(), "close Some(Abnormal)");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keepalive_pong_keeps_connection() {
+        let mut client = TestClient::connect_with(Echo, keepalive_config()).await;
+
+        let start = Instant::now();
+        for _ in 0..3 {
+            let Message::Ping(payload) = client.receive().await else {
+                panic!("expected a ping");
+            };
+            tokio::time::advance(Duration::from_secs(4)).await;
+            client.send(Message::Pong(payload)).await;
+        }
+        assert_eq!(start.elapsed(), Duration::from_secs(3 * 14));
+
+        client.send(Message::Text("still here".to_string())).await;
+        assert_eq!(
+            client.receive().await,
+            Message::Text("still here".to_string())
+        );
+    }
+
+    fn bye() -> Message {
+        Message::Close(Some(CloseFrame {
+            code: CloseCode::Normal,
+            reason: "bye".to_string(),
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_server_initiated_close() {
+        let (events, mut received) = mpsc::unbounded_channel();
+        let mut client = TestClient::connect(Recorder { events, count: 0 }).await;
+        assert_eq!(received.recv().await.unwrap(), "open /chat");
+
+        client.send(Message::Text("bye".to_string())).await;
+        assert_eq!(client.receive().await, bye());
+        assert_eq!(received.recv().await.unwrap(), "late Connection closed");
+
+        // Data may still arrive until the peer's Close
+        client.send(Message::Text("more".to_string())).await;
+        client.send(bye()).await;
+        assert_eq!(received.recv().await.unwrap(), "message Text(\"more\")");
+        assert_eq!(received.recv().await.unwrap(), "close Some(Normal)");
+
+        // The server closes the TCP connection once the handshake completed
+        assert!(matches!(
+            client.reader.read(&mut client.read_half).await,
+            Err(FrameError::Io(_))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_close_timeout() {
+        let (events, mut received) = mpsc::unbounded_channel();
+        let mut client = TestClient::connect(Recorder { events, count: 0 }).await;
+        assert_eq!(received.recv().await.unwrap(), "open /chat");
+
+        client.send(Message::Text("bye".to_string())).await;
+        assert_eq!(client.receive().await, bye());
+        assert_eq!(received.recv().await.unwrap(), "late Connection closed");
+
+        // Never answering the Close gets the connection dropped
+        let start = Instant::now();
+        assert_eq!(received.recv().await.unwrap(), "close Some(Abnormal)");
+        assert_eq!(start.elapsed(), ServerConfig::default().close_timeout);
+        assert!(matches!(
+            client.reader.read(&mut client.read_half).await,
+            Err(FrameError::Io(_))
+        ));
     }
 }
@@ -1,12 +1,20 @@
+use crate::extension::{ExtensionFactory, Negotiated};
 use base64::{engine::general_purpose::STANDARD, Engine as _};
 use sha1::{Digest, Sha1};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 use std::{collections::HashMap, io};
 use thiserror::Error;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio::time::{timeout_at, Instant};
 
 const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
-const REQUIRED_HEADERS: [&str; 3] = ["Sec-WebSocket-Key", "Upgrade", "Connection"];
+const REQUIRED_HEADERS: [&str; 3] = ["sec-websocket-key", "upgrade", "connection"];
+// May be split across several lines, RFC 6455 section 9.1
+const LIST_HEADERS: [&str; 2] = ["sec-websocket-extensions", "sec-websocket-protocol"];
 
+/// Errors from the opening handshake, on either side of the connection.
 #[derive(Error, Debug)]
 pub enum HandshakeError {
     #[error("IO error: {0}")]
@@ -15,40 +23,256 @@ pub enum HandshakeError {
     MissingHeader(String),
     #[error("Invalid header value: {0}")]
     InvalidHeader(String),
+    #[error("Unexpected response status: {0}")]
+    InvalidStatus(String),
+    #[error("Request line longer than {0} bytes")]
+    RequestLineTooLong(usize),
+    #[error("Header line longer than {0} bytes")]
+    HeaderLineTooLong(usize),
+    #[error("Headers larger than {0} bytes in total")]
+    HeadersTooLarge(usize),
+    #[error("More than {0} headers")]
+    TooManyHeaders(usize),
+    #[error("Handshake not completed within {0:?}")]
+    Timeout(Duration),
 }
 
+impl HandshakeError {
+    /// The HTTP status a server answers with before dropping the connection, if any.
+    pub fn status(&self) -> Option<&'static str> {
+        match self {
+            HandshakeError::RequestLineTooLong(_)
+            | HandshakeError::HeaderLineTooLong(_)
+            | HandshakeError::HeadersTooLarge(_)
+            | HandshakeError::TooManyHeaders(_) => Some("431 Request Header Fields Too Large"),
+            HandshakeError::Timeout(_) => Some("408 Request Timeout"),
+            _ => None,
+        }
+    }
+}
+
+/// Caps on the opening request, so a client cannot hold a connection or memory forever.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeLimits {
+    /// Longest request line, such as `GET /path HTTP/1.1`, in bytes.
+    pub max_request_line: usize,
+    /// Longest single header line, in bytes.
+    pub max_header_line: usize,
+    /// Largest size of all header lines together, in bytes.
+    pub max_header_bytes: usize,
+    pub max_headers: usize,
+    /// Time the client has to send its request and read our response.
+    pub timeout: Duration,
+}
+
+impl Default for HandshakeLimits {
+    fn default() -> Self {
+        Self {
+            max_request_line: 8 * 1024,
+            max_header_line: 8 * 1024,
+            max_header_bytes: 64 * 1024,
+            max_headers: 100,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// What the server is willing to negotiate during the opening handshake.
+#[derive(Default, Clone)]
+pub struct HandshakeConfig {
+    pub extensions: Vec<Arc<dyn ExtensionFactory>>,
+    pub subprotocols: Subprotocols,
+    pub limits: HandshakeLimits,
+}
+
+pub type SelectProtocol = Arc<dyn Fn(&[String]) -> Option<String> + Send + Sync>;
+
+/// How the server picks one of the subprotocols offered in Sec-WebSocket-Protocol.
+#[derive(Default, Clone)]
+pub enum Subprotocols {
+    #[default]
+    None,
+    /// Our supported protocols, most preferred first.
+    Preference(Vec<String>),
+    /// Called with the client's offers in their order, must return one of them.
+    Select(SelectProtocol),
+}
+
+/// The outcome of a successful opening handshake.
+#[derive(Default)]
+pub struct Handshake {
+    pub extensions: Vec<Negotiated>,
+    pub protocol: Option<String>,
+    /// The client's request, only filled in on the server side.
+    pub request: Request,
+}
+
+/// The resource and headers of an opening request.
+#[derive(Debug, Default, Clone)]
+pub struct Request {
+    pub path: String,
+    /// Header values by lowercased name, repeated list headers joined with ", ".
+    pub headers: HashMap<String, String>,
+    /// Filled in by the accept loop, the handshake itself knows nothing about the transport.
+    pub peer: Peer,
+}
+
+/// What the transport tells about the other end of a connection.
+#[derive(Debug, Default, Clone)]
+pub struct Peer {
+    pub addr: Option<SocketAddr>,
+    /// Subject of the client certificate, only set once TLS verified it against the CA bundle.
+    pub certificate_subject: Option<String>,
+}
+
+/// One element of a Sec-WebSocket-Extensions header, with its parameters.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ExtensionOffer {
+    pub name: String,
+    pub params: Vec<(String, Option<String>)>,
+}
+
+/// Performs the server side of the opening handshake and answers with 101 Switching Protocols.
+///
+/// Requests breaking the [`HandshakeLimits`] are answered with [`HandshakeError::status`].
 pub async fn do_handshake(
     reader: &mut (impl AsyncBufReadExt + Unpin),
     writer: &mut (impl AsyncWriteExt + Unpin),
-) -> Result<(), HandshakeError> {
-    let headers = read_http_headers(reader).await?;
-    validate_headers(&headers)?;
-    send_response(writer, &headers).await?;
-    Ok(())
+    config: &HandshakeConfig,
+) -> Result<Handshake, HandshakeError> {
+    let deadline = Instant::now() + config.limits.timeout;
+    do_handshake_until(reader, writer, config, deadline).await
 }
 
-async fn read_http_headers(
+/// Like [`do_handshake`], but must finish by `deadline` instead of the configured timeout
+/// from now, so the time a TLS handshake took before counts as well.
+pub async fn do_handshake_until(
     reader: &mut (impl AsyncBufReadExt + Unpin),
-) -> Result<HashMap<String, String>, HandshakeError> {
-    let mut headers = HashMap::new();
-    let mut request_line = String::new();
+    writer: &mut (impl AsyncWriteExt + Unpin),
+    config: &HandshakeConfig,
+    deadline: Instant,
+) -> Result<Handshake, HandshakeError> {
+    let limits = &config.limits;
+    let request = timeout_at(deadline, read_http_headers(reader, limits))
+        .await
+        .unwrap_or(Err(HandshakeError::Timeout(limits.timeout)));
+    let request = match request {
+        Ok(request) => request,
+        Err(e) => {
+            if let Some(status) = e.status() {
+                // Best effort, the connection is dropped either way
+                let _ = timeout_at(deadline, send_status(writer, status)).await;
+            }
+            return Err(e);
+        }
+    };
+
+    validate_headers(&request.headers)?;
+    let mut handshake = negotiate(&request.headers, config);
+    timeout_at(
+        deadline,
+        send_response(writer, &request.headers, &handshake),
+    )
+    .await
+    .unwrap_or(Err(HandshakeError::Timeout(limits.timeout)))?;
+    handshake.request = request;
+    Ok(handshake)
+}
+
+/// Performs the client side of the opening handshake, offering `protocols` to the server.
+pub async fn do_client_handshake(
+    reader: &mut (impl AsyncBufReadExt + Unpin),
+    writer: &mut (impl AsyncWriteExt + Unpin),
+    host: &str,
+    path: &str,
+    protocols: &[String],
+) -> Result<Handshake, HandshakeError> {
+    let key = STANDARD.encode(rand::random::<[u8; 16]>());
+    let request = generate_request(host, path, &key, protocols);
+    writer.write_all(request.as_bytes()).await?;
+    writer.flush().await?;
+
+    let headers = read_http_response(reader).await?;
+    validate_response(&headers, &key, protocols)
+}
 
-    reader.read_line(&mut request_line).await?;
-    if !request_line.trim_end().starts_with("GET") {
+async fn read_http_headers(
+    reader: &mut (impl AsyncBufReadExt + Unpin),
+    limits: &HandshakeLimits,
+) -> Result<Request, HandshakeError> {
+    let request_line = read_line(reader, limits.max_request_line)
+        .await?
+        .ok_or(HandshakeError::RequestLineTooLong(limits.max_request_line))?;
+    let mut parts = request_line.split_whitespace();
+    if parts.next() != Some("GET") {
         return Err(HandshakeError::InvalidHeader(
             "Must be GET request".to_string(),
         ));
     }
+    let path = parts
+        .next()
+        .ok_or_else(|| HandshakeError::InvalidHeader("Missing request target".to_string()))?;
+
+    Ok(Request {
+        path: path.to_string(),
+        headers: read_headers(reader, limits).await?,
+        ..Request::default()
+    })
+}
+
+async fn read_http_response(
+    reader: &mut (impl AsyncBufReadExt + Unpin),
+) -> Result<HashMap<String, String>, HandshakeError> {
+    let limits = HandshakeLimits::default();
+    let status_line = read_line(reader, limits.max_request_line)
+        .await?
+        .ok_or_else(|| HandshakeError::InvalidStatus("Status line too long".to_string()))?;
+    if !status_line.starts_with("HTTP/1.1 101") {
+        return Err(HandshakeError::InvalidStatus(
+            status_line.trim_end().to_string(),
+        ));
+    }
+
+    read_headers(reader, &limits).await
+}
+
+async fn read_headers(
+    reader: &mut (impl AsyncBufReadExt + Unpin),
+    limits: &HandshakeLimits,
+) -> Result<HashMap<String, String>, HandshakeError> {
+    let mut headers: HashMap<String, String> = HashMap::new();
+    let mut count = 0;
+    let mut total = 0;
 
     loop {
-        let mut line = String::new();
-        let bytes_reads = reader.read_line(&mut line).await?;
-        if bytes_reads == 0 || line.trim().is_empty() {
+        let line = read_line(reader, limits.max_header_line)
+            .await?
+            .ok_or(HandshakeError::HeaderLineTooLong(limits.max_header_line))?;
+        if line.trim().is_empty() {
             break;
         }
 
+        count += 1;
+        total += line.len();
+        if count > limits.max_headers {
+            return Err(HandshakeError::TooManyHeaders(limits.max_headers));
+        }
+        if total > limits.max_header_bytes {
+            return Err(HandshakeError::HeadersTooLarge(limits.max_header_bytes));
+        }
+
         if let Some((key, value)) = line.split_once(":") {
-            headers.insert(key.trim().to_string(), value.trim().to_string());
+            // Field names are case-insensitive, they are stored lowercased
+            let (key, value) = (key.trim().to_ascii_lowercase(), value.trim());
+            match headers.get_mut(&key) {
+                Some(values) if LIST_HEADERS.contains(&key.as_str()) => {
+                    values.push_str(", ");
+                    values.push_str(value);
+                }
+                _ => {
+                    headers.insert(key, value.to_string());
+                }
+            }
         } else {
             return Err(HandshakeError::InvalidHeader(
                 "Invalid header format".to_string(),
@@ -56,7 +280,38 @@ async fn read_http_headers(
         }
     }
 
-    return Ok(headers);
+    Ok(headers)
+}
+
+/// Reads up to and including the next newline, `None` if that takes more than `max` bytes.
+/// The line is empty at the end of the stream.
+async fn read_line(
+    reader: &mut (impl AsyncBufReadExt + Unpin),
+    max: usize,
+) -> Result<Option<String>, HandshakeError> {
+    let mut line = Vec::new();
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            break;
+        }
+        let (used, done) = match available.iter().position(|&byte| byte == b'\n') {
+            Some(end) => (end + 1, true),
+            None => (available.len(), false),
+        };
+        if line.len() + used > max {
+            return Ok(None);
+        }
+        line.extend_from_slice(&available[..used]);
+        reader.consume(used);
+        if done {
+            break;
+        }
+    }
+
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| HandshakeError::InvalidHeader("Headers must be valid UTF-8".to_string()))
 }
 
 fn validate_headers(headers: &HashMap<String, String>) -> Result<(), HandshakeError> {
@@ -66,13 +321,13 @@ fn validate_headers(headers: &HashMap<String, String>) -> Result<(), HandshakeEr
         }
     }
 
-    if headers.get("Upgrade").map(|v| v.to_lowercase()) != Some("websocket".to_string()) {
+    if headers.get("upgrade").map(|v| v.to_lowercase()) != Some("websocket".to_string()) {
         return Err(HandshakeError::InvalidHeader(
             "Upgrade header must be websocket".to_string(),
         ));
     }
 
-    if headers.get("Connection").map(|v| v.to_lowercase()) != Some("upgrade".to_string()) {
+    if headers.get("connection").map(|v| v.to_lowercase()) != Some("upgrade".to_string()) {
         return Err(HandshakeError::InvalidHeader(
             "Connection header must be upgrade".to_string(),
         ));
@@ -81,33 +336,254 @@ fn validate_headers(headers: &HashMap<String, String>) -> Result<(), HandshakeEr
     Ok(())
 }
 
+fn negotiate(headers: &HashMap<String, String>, config: &HandshakeConfig) -> Handshake {
+    let offers = headers
+        .get("sec-websocket-extensions")
+        .map(|value| parse_extensions(value))
+        .unwrap_or_default();
+
+    let protocols = headers
+        .get("sec-websocket-protocol")
+        .map(|value| parse_protocols(value))
+        .unwrap_or_default();
+
+    Handshake {
+        extensions: negotiate_extensions(&offers, &config.extensions),
+        protocol: config.subprotocols.select(&protocols),
+        ..Handshake::default()
+    }
+}
+
+impl Subprotocols {
+    fn select(&self, offered: &[String]) -> Option<String> {
+        let protocol = match self {
+            Subprotocols::None => None,
+            Subprotocols::Preference(supported) => supported
+                .iter()
+                .find(|protocol| offered.contains(protocol))
+                .cloned(),
+            Subprotocols::Select(select) => select(offered),
+        };
+
+        // The server may only answer with a protocol the client asked for
+        protocol.filter(|protocol| offered.contains(protocol))
+    }
+}
+
+fn parse_protocols(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|protocol| !protocol.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn negotiate_extensions(
+    offers: &[ExtensionOffer],
+    factories: &[Arc<dyn ExtensionFactory>],
+) -> Vec<Negotiated> {
+    let mut extensions: Vec<Negotiated> = Vec::new();
+    let mut accepted: Vec<&str> = Vec::new();
+    let mut rsv_bits = 0;
+    let mut opcodes: Vec<u8> = Vec::new();
+
+    // Offers are in the client's order of preference, the first acceptable one per extension wins
+    for offer in offers {
+        if accepted.contains(&offer.name.as_str()) {
+            continue;
+        }
+        let Some(factory) = factories
+            .iter()
+            .find(|factory| factory.name() == offer.name)
+        else {
+            continue;
+        };
+        let Some(negotiated) = factory.negotiate(offer) else {
+            continue;
+        };
+
+        // Two extensions cannot share RSV bits or opcodes
+        let bits = negotiated.decoder.rsv_bits() | negotiated.encoder.rsv_bits();
+        let codes = [negotiated.decoder.opcodes(), negotiated.encoder.opcodes()].concat();
+        if bits & rsv_bits != 0 || codes.iter().any(|code| opcodes.contains(code)) {
+            continue;
+        }
+
+        rsv_bits |= bits;
+        opcodes.extend(codes);
+        accepted.push(&offer.name);
+        extensions.push(negotiated);
+    }
+
+    extensions
+}
+
+fn parse_extensions(value: &str) -> Vec<ExtensionOffer> {
+    value
+        .split(',')
+        .filter_map(|offer| {
+            let mut parts = offer.split(';').map(str::trim);
+            let name = parts.next().filter(|name| !name.is_empty())?;
+            let params = parts
+                .filter(|param| !param.is_empty())
+                .map(|param| match param.split_once('=') {
+                    Some((key, value)) => (
+                        key.trim().to_string(),
+                        Some(value.trim().trim_matches('"').to_string()),
+                    ),
+                    None => (param.to_string(), None),
+                })
+                .collect();
+
+            Some(ExtensionOffer {
+                name: name.to_string(),
+                params,
+            })
+        })
+        .collect()
+}
+
 async fn send_response(
     writer: &mut (impl AsyncWriteExt + Unpin),
     headers: &HashMap<String, String>,
+    handshake: &Handshake,
+) -> Result<(), HandshakeError> {
+    let response = generate_response(&headers["sec-websocket-key"], handshake);
+    writer.write_all(response.as_bytes()).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+async fn send_status(
+    writer: &mut (impl AsyncWriteExt + Unpin),
+    status: &str,
 ) -> Result<(), HandshakeError> {
-    let response = generate_response(&headers["Sec-WebSocket-Key"]);
+    let response = format!(
+        "HTTP/1.1 {}\r\n\
+         Connection: close\r\n\
+         Content-Length: 0\r\n\r\n",
+        status
+    );
     writer.write_all(response.as_bytes()).await?;
     writer.flush().await?;
     Ok(())
 }
 
-fn generate_response(key: &str) -> String {
+fn accept_key(key: &str) -> String {
     let mut hasher = Sha1::new();
     hasher.update(format!("{}{}", key, WEBSOCKET_GUID));
     let result = hasher.finalize();
-    let accept_key = STANDARD.encode(result);
-    format!(
+    STANDARD.encode(result)
+}
+
+fn generate_response(key: &str, handshake: &Handshake) -> String {
+    let accept_key = accept_key(key);
+    let mut response = format!(
         "HTTP/1.1 101 Switching Protocols\r\n\
          Upgrade: websocket\r\n\
          Connection: Upgrade\r\n\
-         Sec-WebSocket-Accept: {}\r\n\r\n",
+         Sec-WebSocket-Accept: {}\r\n",
         accept_key
-    )
+    );
+
+    if let Some(protocol) = &handshake.protocol {
+        response.push_str(&format!("Sec-WebSocket-Protocol: {}\r\n", protocol));
+    }
+
+    if !handshake.extensions.is_empty() {
+        let extensions: Vec<&str> = handshake
+            .extensions
+            .iter()
+            .map(|extension| extension.response.as_str())
+            .collect();
+        response.push_str(&format!(
+            "Sec-WebSocket-Extensions: {}\r\n",
+            extensions.join(", ")
+        ));
+    }
+
+    response.push_str("\r\n");
+    response
+}
+
+fn generate_request(host: &str, path: &str, key: &str, protocols: &[String]) -> String {
+    let mut request = format!(
+        "GET {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\n\
+         Sec-WebSocket-Version: 13\r\n",
+        path, host, key
+    );
+
+    if !protocols.is_empty() {
+        request.push_str(&format!(
+            "Sec-WebSocket-Protocol: {}\r\n",
+            protocols.join(", ")
+        ));
+    }
+
+    request.push_str("\r\n");
+    request
+}
+
+fn validate_response(
+    headers: &HashMap<String, String>,
+    key: &str,
+    protocols: &[String],
+) -> Result<Handshake, HandshakeError> {
+    for header in ["sec-websocket-accept", "upgrade", "connection"] {
+        if !headers.contains_key(header) {
+            return Err(HandshakeError::MissingHeader(header.to_string()));
+        }
+    }
+
+    if headers["upgrade"].to_lowercase() != "websocket" {
+        return Err(HandshakeError::InvalidHeader(
+            "Upgrade header must be websocket".to_string(),
+        ));
+    }
+
+    if headers["connection"].to_lowercase() != "upgrade" {
+        return Err(HandshakeError::InvalidHeader(
+            "Connection header must be upgrade".to_string(),
+        ));
+    }
+
+    if headers["sec-websocket-accept"] != accept_key(key) {
+        return Err(HandshakeError::InvalidHeader(
+            "Sec-WebSocket-Accept does not match key".to_string(),
+        ));
+    }
+
+    // We never offer extensions, so the server must not have accepted any
+    if headers.contains_key("sec-websocket-extensions") {
+        return Err(HandshakeError::InvalidHeader(
+            "Unexpected Sec-WebSocket-Extensions".to_string(),
+        ));
+    }
+
+    let protocol = headers.get("sec-websocket-protocol").cloned();
+    if let Some(protocol) = &protocol {
+        if !protocols.contains(protocol) {
+            return Err(HandshakeError::InvalidHeader(
+                "Sec-WebSocket-Protocol was not offered".to_string(),
+            ));
+        }
+    }
+
+    Ok(Handshake {
+        protocol,
+        ..Handshake::default()
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::deflate::DeflateConfig;
     use std::time::Duration;
     use tokio::io::AsyncWriteExt;
     use tokio::io::BufReader;
@@ -148,6 +624,7 @@ mod tests {
 
     impl Drop for MockStream {
         fn drop(&mut self) {
+            #[allow(clippy::let_underscore_future)]
             let _ = self.stream.shutdown();
             self._handle.abort();
         }
@@ -155,7 +632,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_read_http_headers() {
-        let request = "GET / HTTP/1.1\r\n\
+        let request = "GET /chat?room=1 HTTP/1.1\r\n\
             Host: localhost:8080\r\n\
             Upgrade: websocket\r\n\
             Connection: Upgrade\r\n\
@@ -164,14 +641,16 @@ mod tests {
 
         let (reader, _) = mock_stream.stream.split();
         let mut reader = BufReader::new(reader);
-        let headers = read_http_headers(&mut reader)
+        let request = read_http_headers(&mut reader, &HandshakeLimits::default())
             .await
             .expect("Failed to read headers");
+        assert_eq!(request.path, "/chat?room=1");
+        let headers = request.headers;
         assert_eq!(headers.len(), 4);
-        assert_eq!(headers["Upgrade"], "websocket");
-        assert_eq!(headers["Connection"], "Upgrade");
-        assert_eq!(headers["Sec-WebSocket-Key"], "dGhlIHNhbXBsZSBub25jZQ==");
-        assert_eq!(headers["Host"], "localhost:8080");
+        assert_eq!(headers["upgrade"], "websocket");
+        assert_eq!(headers["connection"], "Upgrade");
+        assert_eq!(headers["sec-websocket-key"], "dGhlIHNhbXBsZSBub25jZQ==");
+        assert_eq!(headers["host"], "localhost:8080");
     }
 
     #[tokio::test]
@@ -185,7 +664,7 @@ mod tests {
 
         let (reader, _) = mock_stream.stream.split();
         let mut reader = BufReader::new(reader);
-        let headers = read_http_headers(&mut reader).await;
+        let headers = read_http_headers(&mut reader, &HandshakeLimits::default()).await;
         assert!(
             matches!(headers, Err(HandshakeError::InvalidHeader(s)) if s == "Must be GET request")
         );
@@ -202,7 +681,7 @@ mod tests {
 
         let (reader, _) = mock_stream.stream.split();
         let mut reader = BufReader::new(reader);
-        let headers = read_http_headers(&mut reader).await;
+        let headers = read_http_headers(&mut reader, &HandshakeLimits::default()).await;
         assert!(
             matches!(headers, Err(HandshakeError::InvalidHeader(s)) if s == "Invalid header format")
         );
@@ -211,10 +690,10 @@ mod tests {
     #[tokio::test]
     async fn test_validate_headers() {
         let headers = HashMap::from([
-            ("Upgrade".to_string(), "websocket".to_string()),
-            ("Connection".to_string(), "Upgrade".to_string()),
+            ("upgrade".to_string(), "websocket".to_string()),
+            ("connection".to_string(), "Upgrade".to_string()),
             (
-                "Sec-WebSocket-Key".to_string(),
+                "sec-websocket-key".to_string(),
                 "dGhlIHNhbXBsZSBub25jZQ==".to_string(),
             ),
         ]);
@@ -225,22 +704,22 @@ mod tests {
     #[tokio::test]
     async fn test_validate_headers_missing_key() {
         let headers = HashMap::from([
-            ("Upgrade".to_string(), "websocket".to_string()),
-            ("Connection".to_string(), "Upgrade".to_string()),
+            ("upgrade".to_string(), "websocket".to_string()),
+            ("connection".to_string(), "Upgrade".to_string()),
         ]);
         let result = validate_headers(&headers);
         assert!(
-            matches!(result, Err(HandshakeError::MissingHeader(s)) if s == "Sec-WebSocket-Key")
+            matches!(result, Err(HandshakeError::MissingHeader(s)) if s == "sec-websocket-key")
         );
     }
 
     #[tokio::test]
     async fn test_validate_headers_invalid_upgrade() {
         let headers = HashMap::from([
-            ("Upgrade".to_string(), "http".to_string()),
-            ("Connection".to_string(), "Upgrade".to_string()),
+            ("upgrade".to_string(), "http".to_string()),
+            ("connection".to_string(), "Upgrade".to_string()),
             (
-                "Sec-WebSocket-Key".to_string(),
+                "sec-websocket-key".to_string(),
                 "dGhlIHNhbXBsZSBub25jZQ==".to_string(),
             ),
         ]);
@@ -253,10 +732,10 @@ mod tests {
     #[tokio::test]
     async fn test_validate_headers_invalid_connection() {
         let headers = HashMap::from([
-            ("Upgrade".to_string(), "websocket".to_string()),
-            ("Connection".to_string(), "keep-alive".to_string()),
+            ("upgrade".to_string(), "websocket".to_string()),
+            ("connection".to_string(), "keep-alive".to_string()),
             (
-                "Sec-WebSocket-Key".to_string(),
+                "sec-websocket-key".to_string(),
                 "dGhlIHNhbXBsZSBub25jZQ==".to_string(),
             ),
         ]);
@@ -269,11 +748,215 @@ mod tests {
     #[tokio::test]
     async fn test_generate_response() {
         let key = "dGhlIHNhbXBsZSBub25jZQ==";
-        let response = generate_response(key);
+        let response = generate_response(key, &Handshake::default());
         assert!(response.starts_with("HTTP/1.1 101 Switching Protocols"));
         assert!(response.contains("Upgrade: websocket"));
         assert!(response.contains("Connection: Upgrade"));
         assert!(response.contains("Sec-WebSocket-Accept:"));
+        assert!(!response.contains("Sec-WebSocket-Extensions:"));
+        assert!(response.ends_with("\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_parse_extensions() {
+        let offers = parse_extensions(
+            "permessage-deflate; client_max_window_bits, permessage-deflate; server_max_window_bits=\"10\"",
+        );
+        assert_eq!(
+            offers,
+            vec![
+                ExtensionOffer {
+                    name: "permessage-deflate".to_string(),
+                    params: vec![("client_max_window_bits".to_string(), None)],
+                },
+                ExtensionOffer {
+                    name: "permessage-deflate".to_string(),
+                    params: vec![("server_max_window_bits".to_string(), Some("10".to_string()))],
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_generate_response_with_deflate() {
+        let headers = HashMap::from([(
+            "sec-websocket-extensions".to_string(),
+            "permessage-deflate; client_max_window_bits".to_string(),
+        )]);
+        let config = HandshakeConfig {
+            extensions: vec![Arc::new(DeflateConfig::default())],
+            ..HandshakeConfig::default()
+        };
+        let handshake = negotiate(&headers, &config);
+        assert_eq!(handshake.extensions.len(), 1);
+
+        let response = generate_response("dGhlIHNhbXBsZSBub25jZQ==", &handshake);
+        assert!(response.contains("Sec-WebSocket-Extensions: permessage-deflate\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_offers_across_header_lines() {
+        let request = format!(
+            "GET / HTTP/1.1\r\n{}\
+            Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n\
+            Sec-WebSocket-Extensions: x-other\r\n\
+            Sec-WebSocket-Protocol: graphql-ws\r\n\
+            Sec-WebSocket-Protocol: mqtt\r\n\r\n",
+            UPGRADE_HEADERS
+        );
+        let mut reader = BufReader::new(request.as_bytes());
+        let request = read_http_headers(&mut reader, &HandshakeLimits::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            request.headers["sec-websocket-extensions"],
+            "permessage-deflate; client_max_window_bits, x-other"
+        );
+
+        let config = HandshakeConfig {
+            extensions: vec![Arc::new(DeflateConfig::default())],
+            subprotocols: Subprotocols::Preference(vec!["mqtt".to_string()]),
+            ..HandshakeConfig::default()
+        };
+        let handshake = negotiate(&request.headers, &config);
+        assert_eq!(handshake.extensions.len(), 1);
+        assert_eq!(handshake.extensions[0].response, "permessage-deflate");
+        assert_eq!(handshake.protocol.as_deref(), Some("mqtt"));
+    }
+
+    #[tokio::test]
+    async fn test_lowercase_headers() {
+        let request = "GET / HTTP/1.1\r\n\
+            host: localhost:8080\r\n\
+            upgrade: websocket\r\n\
+            connection: upgrade\r\n\
+            sec-websocket-key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            sec-websocket-version: 13\r\n\
+            sec-websocket-extensions: permessage-deflate\r\n\
+            SEC-WEBSOCKET-PROTOCOL: mqtt\r\n\r\n";
+        let mut reader = BufReader::new(request.as_bytes());
+        let request = read_http_headers(&mut reader, &HandshakeLimits::default())
+            .await
+            .unwrap();
+        assert!(validate_headers(&request.headers).is_ok());
+
+        let config = HandshakeConfig {
+            extensions: vec![Arc::new(DeflateConfig::default())],
+            subprotocols: Subprotocols::Preference(vec!["mqtt".to_string()]),
+            ..HandshakeConfig::default()
+        };
+        let handshake = negotiate(&request.headers, &config);
+        assert_eq!(handshake.extensions.len(), 1);
+        assert_eq!(handshake.protocol.as_deref(), Some("mqtt"));
+
+        let response = "HTTP/1.1 101 Switching Protocols\r\n\
+            upgrade: websocket\r\n\
+            connection: upgrade\r\n\
+            sec-websocket-accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\
+            sec-websocket-protocol: mqtt\r\n\r\n";
+        let mut reader = BufReader::new(response.as_bytes());
+        let headers = read_http_response(&mut reader).await.unwrap();
+        let handshake =
+            validate_response(&headers, "dGhlIHNhbXBsZSBub25jZQ==", &["mqtt".to_string()]).unwrap();
+        assert_eq!(handshake.protocol.as_deref(), Some("mqtt"));
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_deflate_disabled() {
+        let headers = HashMap::from([(
+            "sec-websocket-extensions".to_string(),
+            "permessage-deflate".to_string(),
+        )]);
+        let handshake = negotiate(&headers, &HandshakeConfig::default());
+        assert!(handshake.extensions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_protocol_preference() {
+        let headers = HashMap::from([(
+            "sec-websocket-protocol".to_string(),
+            "mqtt, graphql-transport-ws".to_string(),
+        )]);
+        let config = HandshakeConfig {
+            subprotocols: Subprotocols::Preference(vec![
+                "graphql-transport-ws".to_string(),
+                "mqtt".to_string(),
+            ]),
+            ..HandshakeConfig::default()
+        };
+        let handshake = negotiate(&headers, &config);
+        assert_eq!(handshake.protocol.as_deref(), Some("graphql-transport-ws"));
+
+        let response = generate_response("dGhlIHNhbXBsZSBub25jZQ==", &handshake);
+        assert!(response.contains("Sec-WebSocket-Protocol: graphql-transport-ws\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_protocol_callback() {
+        let headers = HashMap::from([(
+            "sec-websocket-protocol".to_string(),
+            "v1.chat, v2.chat".to_string(),
+        )]);
+        let config = HandshakeConfig {
+            subprotocols: Subprotocols::Select(Arc::new(|offered| offered.last().cloned())),
+            ..HandshakeConfig::default()
+        };
+        let handshake = negotiate(&headers, &config);
+        assert_eq!(handshake.protocol.as_deref(), Some("v2.chat"));
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_protocol_not_offered() {
+        let headers = HashMap::from([("sec-websocket-protocol".to_string(), "mqtt".to_string())]);
+        let config = HandshakeConfig {
+            subprotocols: Subprotocols::Select(Arc::new(|_| Some("graphql-ws".to_string()))),
+            ..HandshakeConfig::default()
+        };
+        let handshake = negotiate(&headers, &config);
+        assert!(handshake.protocol.is_none());
+
+        let response = generate_response("dGhlIHNhbXBsZSBub25jZQ==", &handshake);
+        assert!(!response.contains("Sec-WebSocket-Protocol"));
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_falls_back_to_next_offer() {
+        let offers = parse_extensions(
+            "permessage-deflate; server_max_window_bits=8, permessage-deflate; client_max_window_bits",
+        );
+        let factories: Vec<Arc<dyn ExtensionFactory>> = vec![Arc::new(DeflateConfig::default())];
+        let extensions = negotiate_extensions(&offers, &factories);
+        assert_eq!(extensions.len(), 1);
+        assert_eq!(extensions[0].response, "permessage-deflate");
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_skips_conflicting_extensions() {
+        struct Other;
+
+        impl ExtensionFactory for Other {
+            fn name(&self) -> &str {
+                "x-other"
+            }
+
+            fn negotiate(&self, offer: &ExtensionOffer) -> Option<Negotiated> {
+                // Claims the same RSV1 bit as permessage-deflate
+                DeflateConfig::default()
+                    .negotiate(offer)
+                    .map(|mut negotiated| {
+                        negotiated.response = self.name().to_string();
+                        negotiated
+                    })
+            }
+        }
+
+        let offers = parse_extensions("x-other, permessage-deflate");
+        let factories: Vec<Arc<dyn ExtensionFactory>> =
+            vec![Arc::new(DeflateConfig::default()), Arc::new(Other)];
+        let extensions = negotiate_extensions(&offers, &factories);
+        assert_eq!(extensions.len(), 1);
+        assert_eq!(extensions[0].response, "x-other");
     }
 
     #[tokio::test]
@@ -288,7 +971,7 @@ mod tests {
         let (reader, writer) = mock_stream.stream.split();
         let mut reader = BufReader::new(reader);
         let mut writer = BufWriter::new(writer);
-        let result = do_handshake(&mut reader, &mut writer).await;
+        let result = do_handshake(&mut reader, &mut writer, &HandshakeConfig::default()).await;
         assert!(result.is_ok());
     }
 
@@ -303,9 +986,213 @@ mod tests {
         let (reader, writer) = mock_stream.stream.split();
         let mut reader = BufReader::new(reader);
         let mut writer = BufWriter::new(writer);
-        let result = do_handshake(&mut reader, &mut writer).await;
+        let result = do_handshake(&mut reader, &mut writer, &HandshakeConfig::default()).await;
+        assert!(
+            matches!(result, Err(HandshakeError::MissingHeader(s)) if s == "sec-websocket-key")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_generate_request() {
+        let request = generate_request(
+            "localhost:8080",
+            "/chat",
+            "dGhlIHNhbXBsZSBub25jZQ==",
+            &["mqtt".to_string()],
+        );
+        assert!(request.starts_with("GET /chat HTTP/1.1\r\n"));
+        assert!(request.contains("Host: localhost:8080\r\n"));
+        assert!(request.contains("Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n"));
+        assert!(request.contains("Sec-WebSocket-Version: 13\r\n"));
+        assert!(request.contains("Sec-WebSocket-Protocol: mqtt\r\n"));
+        assert!(request.ends_with("\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_validate_response_accept_key() {
+        let key = "dGhlIHNhbXBsZSBub25jZQ==";
+        let mut headers = HashMap::from([
+            ("upgrade".to_string(), "websocket".to_string()),
+            ("connection".to_string(), "Upgrade".to_string()),
+            (
+                "sec-websocket-accept".to_string(),
+                // RFC 6455 section 1.3
+                "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=".to_string(),
+            ),
+        ]);
+        assert!(validate_response(&headers, key, &[]).is_ok());
+
+        headers.insert(
+            "sec-websocket-accept".to_string(),
+            "AAAAAAAAAAAAAAAAAAAAAAAAAAA=".to_string(),
+        );
+        let result = validate_response(&headers, key, &[]);
+        assert!(
+            matches!(result, Err(HandshakeError::InvalidHeader(s)) if s == "Sec-WebSocket-Accept does not match key")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_handshake_against_server() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (server_read, mut server_write) = tokio::io::split(server);
+        let (client_read, mut client_write) = tokio::io::split(client);
+
+        let server = tokio::spawn(async move {
+            let config = HandshakeConfig {
+                subprotocols: Subprotocols::Preference(vec!["mqtt".to_string()]),
+                ..HandshakeConfig::default()
+            };
+            let mut server_read = BufReader::new(server_read);
+            do_handshake(&mut server_read, &mut server_write, &config)
+                .await
+                .map(|handshake| handshake.protocol)
+        });
+
+        let mut client_read = BufReader::new(client_read);
+        let handshake = do_client_handshake(
+            &mut client_read,
+            &mut client_write,
+            "localhost",
+            "/",
+            &["graphql-ws".to_string(), "mqtt".to_string()],
+        )
+        .await
+        .unwrap();
+        assert_eq!(handshake.protocol.as_deref(), Some("mqtt"));
+        assert_eq!(server.await.unwrap().unwrap().as_deref(), Some("mqtt"));
+    }
+
+    #[tokio::test]
+    async fn test_client_handshake_rejected() {
+        let response = "HTTP/1.1 400 Bad Request\r\n\r\n";
+        let mut reader = BufReader::new(response.as_bytes());
+        let mut writer = Vec::new();
+        let result = do_client_handshake(&mut reader, &mut writer, "localhost", "/", &[]).await;
         assert!(
-            matches!(result, Err(HandshakeError::MissingHeader(s)) if s == "Sec-WebSocket-Key")
+            matches!(result, Err(HandshakeError::InvalidStatus(s)) if s == "HTTP/1.1 400 Bad Request")
+        );
+    }
+
+    const UPGRADE_HEADERS: &str = "Host: localhost:8080\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n";
+
+    /// Runs the server handshake on `request`, returning its error and our response.
+    async fn reject(request: &str, limits: HandshakeLimits) -> (HandshakeError, String) {
+        let config = HandshakeConfig {
+            limits,
+            ..HandshakeConfig::default()
+        };
+        let mut reader = BufReader::new(request.as_bytes());
+        let mut response = Vec::new();
+        let error = do_handshake(&mut reader, &mut response, &config)
+            .await
+            .err()
+            .expect("Handshake should fail");
+        (error, String::from_utf8(response).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_handshake_within_limits() {
+        let request = format!("GET / HTTP/1.1\r\n{}\r\n", UPGRADE_HEADERS);
+        let limits = HandshakeLimits {
+            max_request_line: 16,
+            max_headers: 4,
+            ..HandshakeLimits::default()
+        };
+        let config = HandshakeConfig {
+            limits,
+            ..HandshakeConfig::default()
+        };
+        let mut reader = BufReader::new(request.as_bytes());
+        let result = do_handshake(&mut reader, &mut Vec::new(), &config).await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_request_line_too_long() {
+        let request = format!(
+            "GET /{} HTTP/1.1\r\n{}\r\n",
+            "a".repeat(100),
+            UPGRADE_HEADERS
+        );
+        let limits = HandshakeLimits {
+            max_request_line: 64,
+            ..HandshakeLimits::default()
+        };
+        let (error, response) = reject(&request, limits).await;
+        assert!(matches!(error, HandshakeError::RequestLineTooLong(64)));
+        assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_header_line_too_long() {
+        // No line ending ever comes, the limit still ends the read
+        let request = format!("GET / HTTP/1.1\r\nCookie: {}", "a".repeat(1 << 20));
+        let (error, response) = reject(&request, HandshakeLimits::default()).await;
+        assert!(matches!(error, HandshakeError::HeaderLineTooLong(8192)));
+        assert!(response.starts_with("HTTP/1.1 431 "));
+    }
+
+    #[tokio::test]
+    async fn test_too_many_headers() {
+        let request = format!(
+            "GET / HTTP/1.1\r\n{}{}\r\n",
+            UPGRADE_HEADERS,
+            "X-Filler: 1\r\n".repeat(10)
+        );
+        let limits = HandshakeLimits {
+            max_headers: 8,
+            ..HandshakeLimits::default()
+        };
+        let (error, response) = reject(&request, limits).await;
+        assert!(matches!(error, HandshakeError::TooManyHeaders(8)));
+        assert!(response.starts_with("HTTP/1.1 431 "));
+    }
+
+    #[tokio::test]
+    async fn test_headers_too_large() {
+        let request = format!(
+            "GET / HTTP/1.1\r\n{}X-Filler: {}\r\n\r\n",
+            UPGRADE_HEADERS,
+            "a".repeat(100)
         );
+        let limits = HandshakeLimits {
+            max_header_bytes: 128,
+            ..HandshakeLimits::default()
+        };
+        let (error, response) = reject(&request, limits).await;
+        assert!(matches!(error, HandshakeError::HeadersTooLarge(128)));
+        assert!(response.starts_with("HTTP/1.1 431 "));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_handshake_timeout() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (server_read, mut server_write) = tokio::io::split(server);
+        let (mut client_read, mut client_write) = tokio::io::split(client);
+
+        // A slow client trickling in its headers without ever finishing them
+        client_write
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n")
+            .await
+            .unwrap();
+        let mut server_read = BufReader::new(server_read);
+        let result = do_handshake(
+            &mut server_read,
+            &mut server_write,
+            &HandshakeConfig::default(),
+        )
+        .await;
+        assert!(matches!(result, Err(HandshakeError::Timeout(t)) if t == Duration::from_secs(10)));
+
+        let mut response = [0; 1024];
+        let read = tokio::io::AsyncReadExt::read(&mut client_read, &mut response)
+            .await
+            .unwrap();
+        let response = String::from_utf8_lossy(&response[..read]);
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
     }
 }
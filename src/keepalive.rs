@@ -0,0 +1,130 @@
+use std::time::Duration;
+
+use crate::message::Message;
+use tokio::time::Instant;
+
+/// When a server pings a quiet peer and how long it waits for an answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    /// Silence after which a Ping is sent.
+    pub interval: Duration,
+    /// Time the peer has to answer a Ping, with the matching Pong or any other message.
+    pub timeout: Duration,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// What to do once [`Timer::deadline`] passed.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Tick {
+    Ping(Vec<u8>),
+    TimedOut,
+}
+
+/// Tracks the last sign of life from the peer and the Ping waiting for its Pong.
+pub(crate) struct Timer {
+    keepalive: Keepalive,
+    // When the next Ping is due, or when the unanswered one expires
+    deadline: Instant,
+    pending: Option<Vec<u8>>,
+    sent: u64,
+}
+
+impl Timer {
+    pub(crate) fn new(keepalive: Keepalive) -> Self {
+        Self {
+            keepalive,
+            deadline: Instant::now() + keepalive.interval,
+            pending: None,
+            sent: 0,
+        }
+    }
+
+    pub(crate) fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Records a message from the peer, Pongs only count when they answer our Ping.
+    pub(crate) fn received(&mut self, message: &Message) {
+        if let (Message::Pong(data), Some(pending)) = (message, &self.pending) {
+            if data != pending {
+                return;
+            }
+        }
+        self.pending = None;
+        self.deadline = Instant::now() + self.keepalive.interval;
+    }
+
+    pub(crate) fn expired(&mut self) -> Tick {
+        if self.pending.is_some() {
+            return Tick::TimedOut;
+        }
+
+        let payload = self.sent.to_be_bytes().to_vec();
+        self.sent += 1;
+        self.pending = Some(payload.clone());
+        self.deadline = Instant::now() + self.keepalive.timeout;
+        Tick::Ping(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEEPALIVE: Keepalive = Keepalive {
+        interval: Duration::from_secs(10),
+        timeout: Duration::from_secs(5),
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timer_ping_then_timeout() {
+        let start = Instant::now();
+        let mut timer = Timer::new(KEEPALIVE);
+        assert_eq!(timer.deadline(), start + KEEPALIVE.interval);
+
+        tokio::time::sleep_until(timer.deadline()).await;
+        assert_eq!(timer.expired(), Tick::Ping(0u64.to_be_bytes().to_vec()));
+        assert_eq!(timer.deadline(), start + Duration::from_secs(15));
+
+        tokio::time::sleep_until(timer.deadline()).await;
+        assert_eq!(timer.expired(), Tick::TimedOut);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timer_matching_pong() {
+        let mut timer = Timer::new(KEEPALIVE);
+        tokio::time::sleep_until(timer.deadline()).await;
+        let Tick::Ping(payload) = timer.expired() else {
+            panic!("expected a ping");
+        };
+
+        // A stale Pong does not answer the outstanding Ping
+        tokio::time::advance(Duration::from_secs(1)).await;
+        let deadline = timer.deadline();
+        timer.received(&Message::Pong(b"stale".to_vec()));
+        assert_eq!(timer.deadline(), deadline);
+
+        timer.received(&Message::Pong(payload));
+        assert_eq!(timer.deadline(), Instant::now() + KEEPALIVE.interval);
+        tokio::time::sleep_until(timer.deadline()).await;
+        assert_eq!(timer.expired(), Tick::Ping(1u64.to_be_bytes().to_vec()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timer_data_counts_as_activity() {
+        let mut timer = Timer::new(KEEPALIVE);
+        tokio::time::sleep_until(timer.deadline()).await;
+        timer.expired();
+
+        timer.received(&Message::Text("alive".to_string()));
+        assert_eq!(timer.deadline(), Instant::now() + KEEPALIVE.interval);
+    }
+}
@@ -0,0 +1,69 @@
+//! A WebSocket (RFC 6455) implementation on top of tokio.
+//!
+//! The crate is layered so each piece can be used on its own:
+//!
+//! - [`frame`] holds the wire types: [`Frame`], [`Opcode`], [`CloseCode`] and [`FrameError`].
+//! - [`Message`] is what applications read and write, text is always valid UTF-8.
+//! - [`Reader`] and [`Writer`] are the codec, turning bytes into frames and back. Large
+//!   messages can be read a [`Chunk`] at a time instead of whole, and sent straight from an
+//!   `AsyncRead` with [`Writer::write_from`].
+//! - [`handshake`] performs the opening handshake for servers ([`do_handshake`]) and
+//!   clients ([`do_client_handshake`]).
+//! - [`extension`] and [`deflate`] negotiate and apply extensions such as permessage-deflate.
+//! - [`serve`] drives a server connection with an application [`Handler`], such as [`Echo`],
+//!   and [`run`] accepts connections until a [`Shutdown`] is triggered.
+//! - With the `tls` feature, `tls` loads certificates and `run_tls` serves wss:// over rustls,
+//!   optionally verifying client certificates and reloading them while running.
+//! - [`Client`] ties everything together for clients, [`split`] hands a connection's reading
+//!   and writing to different tasks.
+//! - [`WebSocketStream`] exposes a connection over any transport as a futures `Stream` and `Sink`.
+//!
+//! ```no_run
+//! use rws::{serve, Echo, ServerConfig};
+//! use tokio::net::TcpListener;
+//!
+//! # async fn run() -> std::io::Result<()> {
+//! let listener = TcpListener::bind("127.0.0.1:8080").await?;
+//! loop {
+//!     let (stream, _) = listener.accept().await?;
+//!     tokio::spawn(async move {
+//!         let _ = serve(stream, &ServerConfig::default(), Echo).await;
+//!     });
+//! }
+//! # }
+//! ```
+
+pub mod client;
+pub mod deflate;
+pub mod extension;
+pub mod frame;
+pub mod handler;
+pub mod handshake;
+pub mod keepalive;
+pub mod message;
+pub mod reader;
+pub mod server;
+pub mod split;
+pub mod stream;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod writer;
+
+pub use client::{Client, ClientConfig};
+pub use frame::{CloseCode, Frame, FrameError, Opcode, Role};
+pub use handler::{serve, serve_until, Echo, Handler, ServerConfig};
+pub use handshake::{
+    do_client_handshake, do_handshake, do_handshake_until, Handshake, HandshakeConfig,
+    HandshakeError, HandshakeLimits, Peer, Request,
+};
+pub use keepalive::Keepalive;
+pub use message::{CloseFrame, Message};
+pub use reader::{Chunk, Limits, Reader};
+#[cfg(feature = "tls")]
+pub use server::run_tls;
+pub use server::{run, shutdown_signal, Shutdown};
+pub use split::{split, CloseState, WsReadHalf, WsWriteHalf};
+pub use stream::WebSocketStream;
+#[cfg(feature = "tls")]
+pub use tls::{reload_on_change, ClientAuth, ReloadableAcceptor, TlsConfig, TlsError};
+pub use writer::Writer;
@@ -0,0 +1,164 @@
+use crate::frame::{CloseCode, Frame, FrameError, Opcode};
+
+/// A complete message as seen by applications.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<CloseFrame>),
+    /// A frame with a reserved opcode, only produced when an extension claims it.
+    Frame(Frame),
+}
+
+/// The status code and reason of a Close frame.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CloseFrame {
+    pub code: CloseCode,
+    pub reason: String,
+}
+
+impl Message {
+    /// Converts a frame the [`Reader`](crate::Reader) already reassembled and validated.
+    pub(crate) fn from_validated(frame: Frame) -> Result<Self, FrameError> {
+        Ok(match frame.opcode {
+            Opcode::Text => {
+                Message::Text(String::from_utf8(frame.data).map_err(|_| FrameError::InvalidUTF8)?)
+            }
+            Opcode::Binary => Message::Binary(frame.data),
+            Opcode::Ping => Message::Ping(frame.data),
+            Opcode::Pong => Message::Pong(frame.data),
+            Opcode::Close => Message::Close(CloseFrame::parse(frame.data)?),
+            Opcode::Continuation | Opcode::Reserved(_) => Message::Frame(frame),
+        })
+    }
+
+    /// The Close message answering `close`, echoing its status unless it may not be sent.
+    pub(crate) fn close_reply(close: Option<CloseFrame>) -> Self {
+        Message::Close(match close {
+            Some(close) if !close.code.is_allowed() => Some(CloseFrame {
+                code: CloseCode::Protocol,
+                reason: close.reason,
+            }),
+            close => close,
+        })
+    }
+}
+
+/// The longest reason that fits a Close frame next to its status code.
+const MAX_REASON_LEN: usize = 123;
+
+impl CloseFrame {
+    /// A Close frame with `reason` cut on a char boundary to fit a control frame.
+    pub(crate) fn truncated(code: CloseCode, reason: &str) -> Self {
+        let mut end = reason.len().min(MAX_REASON_LEN);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        Self {
+            code,
+            reason: reason[..end].to_string(),
+        }
+    }
+
+    /// The Close frame failing a connection with `e`, `None` when no status applies.
+    pub(crate) fn from_error(e: &FrameError) -> Option<Self> {
+        e.close_code()
+            .map(|code| Self::truncated(code, &e.to_string()))
+    }
+
+    fn parse(data: Vec<u8>) -> Result<Option<Self>, FrameError> {
+        match data.len() {
+            0 => Ok(None),
+            1 => Err(FrameError::InvalidCloseFrame),
+            _ => {
+                let code = CloseCode::from(u16::from_be_bytes([data[0], data[1]]));
+                let reason =
+                    String::from_utf8(data[2..].to_vec()).map_err(|_| FrameError::InvalidUTF8)?;
+                Ok(Some(Self { code, reason }))
+            }
+        }
+    }
+}
+
+impl From<Message> for Frame {
+    fn from(message: Message) -> Self {
+        match message {
+            Message::Text(text) => Frame::new(Opcode::Text, text.into_bytes()),
+            Message::Binary(data) => Frame::new(Opcode::Binary, data),
+            Message::Ping(data) => Frame::new(Opcode::Ping, data),
+            Message::Pong(data) => Frame::new(Opcode::Pong, data),
+            Message::Close(None) => Frame::new(Opcode::Close, Vec::new()),
+            Message::Close(Some(close)) => Frame::close(close.code.into(), close.reason.as_bytes()),
+            Message::Frame(frame) => frame,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_close_frame_round_trip() {
+        let message = Message::Close(Some(CloseFrame {
+            code: CloseCode::Away,
+            reason: "bye".to_string(),
+        }));
+        let frame = Frame::from(message.clone());
+        assert_eq!(frame.opcode, Opcode::Close);
+        assert_eq!(frame.data, b"\x03\xe9bye");
+        assert_eq!(Message::from_validated(frame).unwrap(), message);
+    }
+
+    #[test]
+    fn test_close_frame_empty() {
+        let frame = Frame::new(Opcode::Close, Vec::new());
+        assert_eq!(
+            Message::from_validated(frame).unwrap(),
+            Message::Close(None)
+        );
+    }
+
+    #[test]
+    fn test_close_frame_truncated() {
+        let close = CloseFrame::truncated(CloseCode::Normal, &"é".repeat(100));
+        assert_eq!(close.reason, "é".repeat(61));
+        assert!(Frame::from(Message::Close(Some(close))).data.len() <= 125);
+        assert_eq!(
+            CloseFrame::truncated(CloseCode::Normal, "bye").reason,
+            "bye"
+        );
+    }
+
+    #[test]
+    fn test_close_frame_from_error() {
+        assert_eq!(
+            CloseFrame::from_error(&FrameError::InvalidUTF8),
+            Some(CloseFrame {
+                code: CloseCode::Invalid,
+                reason: "Invalid UTF-8".to_string(),
+            })
+        );
+        assert_eq!(CloseFrame::from_error(&FrameError::ConnectionClosed), None);
+    }
+
+    #[test]
+    fn test_text_invalid_utf8() {
+        let frame = Frame::new(Opcode::Text, vec![0xce, 0xba, 0xff]);
+        assert!(matches!(
+            Message::from_validated(frame),
+            Err(FrameError::InvalidUTF8)
+        ));
+    }
+
+    #[test]
+    fn test_close_frame_invalid_reason() {
+        let frame = Frame::new(Opcode::Close, vec![0x03, 0xe8, 0xff]);
+        assert!(matches!(
+            Message::from_validated(frame),
+            Err(FrameError::InvalidUTF8)
+        ));
+    }
+}
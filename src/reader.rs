@@ -1,21 +1,80 @@
-use std::fmt::Binary;
+use std::io;
 
-use crate::frame::{self, Frame, FrameError, Opcode};
+use crate::extension::Extension;
+use crate::frame::{apply_mask, Frame, FrameError, Opcode, Role, RSV1, RSV2, RSV3};
+use crate::message::Message;
 use tokio::io::AsyncReadExt;
 
+/// Largest frame payload accepted unless configured otherwise.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+/// Largest reassembled, decompressed message accepted unless configured otherwise.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+/// Most frames a message may be split into unless configured otherwise. The Autobahn suite
+/// sends messages in 65536 fragments.
+pub const DEFAULT_MAX_FRAGMENTS: usize = 128 * 1024;
+
+// Payloads are read into a buffer that starts at most this large and grows as data arrives,
+// so a claimed length costs nothing until the bytes are actually sent
+const INITIAL_PAYLOAD_CAPACITY: usize = 64 * 1024;
+
+/// Caps on what the peer can make us buffer, exceeding one closes with 1009.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_frame_size: usize,
+    pub max_message_size: usize,
+    pub max_fragments: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_fragments: DEFAULT_MAX_FRAGMENTS,
+        }
+    }
+}
+
+/// Reads frames from a stream and reassembles them into complete messages.
 pub struct Reader {
-    max_payload_size: usize,
-    fragments: Fragments
+    role: Role,
+    limits: Limits,
+    fragments: Fragments,
+    extensions: Vec<Box<dyn Extension>>,
 }
 
+/// Buffers the frames of a fragmented message, validating text as it arrives.
 pub struct Fragments {
     fragments: Option<Fragment>,
     op_code: Opcode,
+    limits: Limits,
+    // Frames of the message in `fragments` so far
+    count: usize,
 }
 
 pub enum Fragment {
     Text(Option<utf8::Incomplete>, Vec<u8>),
     Binary(Vec<u8>),
+    // Messages with RSV bits set are only validated once every extension decoded them
+    Encoded(u8, Vec<u8>),
+    // Handed out frame by frame, only a character split between frames is kept
+    Streamed(Option<utf8::Incomplete>),
+}
+
+/// Part of a message handed out as it arrives, see [`Reader::read_chunk`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Chunk {
+    /// Text split where a frame ended, but never inside a character.
+    Text {
+        data: String,
+        fin: bool,
+    },
+    Binary {
+        data: Vec<u8>,
+        fin: bool,
+    },
+    /// A control frame, or a message extensions had to decode as a whole.
+    Message(Message),
 }
 
 impl Fragment {
@@ -23,84 +82,146 @@ impl Fragment {
         match self {
             Fragment::Binary(buffer) => buffer,
             Fragment::Text(_, buffer) => buffer,
+            Fragment::Encoded(_, buffer) => buffer,
+            Fragment::Streamed(_) => Vec::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Fragment::Binary(buffer) => buffer.len(),
+            Fragment::Text(_, buffer) => buffer.len(),
+            Fragment::Encoded(_, buffer) => buffer.len(),
+            Fragment::Streamed(_) => 0,
+        }
+    }
+}
+
+/// Appends the valid UTF-8 in `data` to `text`, keeping a character cut off at the end of
+/// a frame in `incomplete` until the next one completes it.
+fn decode_text(
+    incomplete: &mut Option<utf8::Incomplete>,
+    data: &[u8],
+    text: &mut Vec<u8>,
+) -> Result<(), FrameError> {
+    validate_text(incomplete, data, |valid| {
+        text.extend_from_slice(valid.as_bytes())
+    })
+}
+
+/// Checks that `data` continues valid UTF-8, handing each validated piece to `valid`.
+pub(crate) fn validate_text(
+    incomplete: &mut Option<utf8::Incomplete>,
+    data: &[u8],
+    mut valid: impl FnMut(&str),
+) -> Result<(), FrameError> {
+    let mut tail = data;
+    if let Some(mut pending) = incomplete.take() {
+        match pending.try_complete(data) {
+            Some((Ok(character), rest)) => {
+                valid(character);
+                tail = rest;
+            }
+            Some((Err(_), _)) => return Err(FrameError::InvalidUTF8),
+            None => {
+                *incomplete = Some(pending);
+                return Ok(());
+            }
         }
     }
+
+    match utf8::decode(tail) {
+        Ok(text) => valid(text),
+        Err(utf8::DecodeError::Incomplete {
+            valid_prefix,
+            incomplete_suffix,
+        }) => {
+            valid(valid_prefix);
+            *incomplete = Some(incomplete_suffix);
+        }
+        Err(utf8::DecodeError::Invalid { .. }) => return Err(FrameError::InvalidUTF8),
+    }
+    Ok(())
+}
+
+impl Default for Fragments {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Fragments {
     pub fn new() -> Self {
-        return Fragments {
+        Self::with_limits(Limits::default())
+    }
+
+    /// Enforces the message size and fragment count of `limits`, frame sizes are up to the
+    /// [`Reader`].
+    pub fn with_limits(limits: Limits) -> Self {
+        Fragments {
             fragments: None,
             op_code: Opcode::Close,
-        };
+            limits,
+            count: 0,
+        }
     }
 
     pub fn accumulate(&mut self, frame: Frame) -> Result<Option<Frame>, FrameError> {
         match frame.opcode {
-            Opcode::Text | Opcode::Binary => {
+            Opcode::Text | Opcode::Binary | Opcode::Reserved(_) if !frame.opcode.is_control() => {
+                // A new message cannot start before the previous one is finished
+                if self.fragments.is_some() {
+                    return Err(FrameError::InvalidFragment);
+                }
+                if frame.data.len() > self.limits.max_message_size {
+                    return Err(FrameError::MessageTooLarge);
+                }
+                self.count = 1;
+
                 if frame.fin {
-                    if self.fragments.is_some() {
-                        return Err(FrameError::InvalidFragment);
-                    }
-                    if frame.opcode == Opcode::Text && !simdutf8::basic::from_utf8(&frame.data).is_ok() {
+                    if frame.opcode == Opcode::Text
+                        && frame.rsv == 0
+                        && simdutf8::basic::from_utf8(&frame.data).is_err()
+                    {
                         return Err(FrameError::InvalidUTF8);
                     }
                     return Ok(Some(frame));
                 }
 
                 self.fragments = match frame.opcode {
-                    Opcode::Text => match utf8::decode(&frame.data) {
-                        Ok(text) => Some(Fragment::Text(None, text.as_bytes().to_vec())),
-                        Err(utf8::DecodeError::Incomplete {
-                            valid_prefix,
-                            incomplete_suffix,
-                        }) => Some(Fragment::Text(
-                            Some(incomplete_suffix),
-                            valid_prefix.as_bytes().to_vec(),
-                        )),
-                        Err(utf8::DecodeError::Invalid { .. }) => {
-                            return Err(FrameError::InvalidUTF8)
-                        }
-                    },
-                    Opcode::Binary => Some(Fragment::Binary(frame.data)),
-                    _ => unreachable!(),
+                    _ if frame.rsv != 0 => Some(Fragment::Encoded(frame.rsv, frame.data)),
+                    Opcode::Text => {
+                        let mut incomplete = None;
+                        let mut text = Vec::with_capacity(frame.data.len());
+                        decode_text(&mut incomplete, &frame.data, &mut text)?;
+                        Some(Fragment::Text(incomplete, text))
+                    }
+                    _ => Some(Fragment::Binary(frame.data)),
                 };
                 self.op_code = frame.opcode;
             }
             Opcode::Continuation => match self.fragments.as_mut() {
-                None => return Err(FrameError::InvalidContinuation(frame.opcode as u8)),
+                None => return Err(FrameError::InvalidContinuation(frame.opcode.into())),
+                // The message is being streamed, see `Fragments::stream`
+                Some(Fragment::Streamed(_)) => return Err(FrameError::InvalidFragment),
+                // Checked before buffering so the limits bound memory, not just the result
+                Some(fragment)
+                    if fragment.len() + frame.data.len() > self.limits.max_message_size =>
+                {
+                    return Err(FrameError::MessageTooLarge)
+                }
+                Some(_) if self.count == self.limits.max_fragments => {
+                    return Err(FrameError::TooManyFragments)
+                }
                 Some(Fragment::Text(data, input)) => {
-                    let mut tail = &frame.data[..];
-                    if let Some(mut incomplete) = data.take() {
-                        if let Some((result, rest)) = incomplete.try_complete(&frame.data) {
-                            tail = rest;
-                            match result {
-                                Ok(text) => input.extend_from_slice(text.as_bytes()),
-                                Err(_) => return Err(FrameError::InvalidUTF8),
-                            }
-                        } else {
-                            tail = &[];
-                            data.replace(incomplete);
-                        }
-                    }
-
-                    match utf8::decode(tail) {
-                        Ok(text) => {
-                            input.extend_from_slice(text.as_bytes());
-                        }
-                        Err(utf8::DecodeError::Incomplete {
-                            valid_prefix,
-                            incomplete_suffix,
-                        }) => {
-                            input.extend_from_slice(valid_prefix.as_bytes());
-                            data.replace(incomplete_suffix);
-                        }
-                        Err(utf8::DecodeError::Invalid { .. }) => {
-                            return Err(FrameError::InvalidUTF8)
-                        }
-                    }
+                    self.count += 1;
+                    decode_text(data, &frame.data, input)?;
 
                     if frame.fin {
+                        // The message cannot end in the middle of a character
+                        if data.is_some() {
+                            return Err(FrameError::InvalidUTF8);
+                        }
                         return Ok(Some(Frame::new(
                             self.op_code,
                             self.fragments.take().unwrap().take_buffer(),
@@ -108,6 +229,7 @@ impl Fragments {
                     }
                 }
                 Some(Fragment::Binary(data)) => {
+                    self.count += 1;
                     data.extend_from_slice(&frame.data);
                     if frame.fin {
                         return Ok(Some(Frame::new(
@@ -116,52 +238,196 @@ impl Fragments {
                         )));
                     }
                 }
+                Some(Fragment::Encoded(rsv, data)) => {
+                    self.count += 1;
+                    let rsv = *rsv;
+                    data.extend_from_slice(&frame.data);
+                    if frame.fin {
+                        let mut message =
+                            Frame::new(self.op_code, self.fragments.take().unwrap().take_buffer());
+                        message.rsv = rsv;
+                        return Ok(Some(message));
+                    }
+                }
             },
             _ => return Ok(Some(frame)),
         }
 
         Ok(None)
     }
+
+    /// Whether [`Fragments::stream`] takes `frame`: data frames no extension has to decode.
+    pub fn streams(&self, frame: &Frame) -> bool {
+        match frame.opcode {
+            Opcode::Text | Opcode::Binary => frame.rsv == 0,
+            Opcode::Continuation => matches!(self.fragments, Some(Fragment::Streamed(_))),
+            _ => false,
+        }
+    }
+
+    /// Hands out a data frame right away instead of buffering it, text is still validated
+    /// across frame boundaries.
+    pub fn stream(&mut self, frame: Frame) -> Result<Chunk, FrameError> {
+        let mut incomplete = match (frame.opcode, self.fragments.take()) {
+            (Opcode::Continuation, Some(Fragment::Streamed(incomplete))) => incomplete,
+            (Opcode::Continuation, None) => {
+                return Err(FrameError::InvalidContinuation(frame.opcode.into()))
+            }
+            (_, Some(_)) => return Err(FrameError::InvalidFragment),
+            (opcode, None) => {
+                self.op_code = opcode;
+                None
+            }
+        };
+
+        let chunk = if self.op_code == Opcode::Text {
+            let mut text = Vec::with_capacity(frame.data.len());
+            decode_text(&mut incomplete, &frame.data, &mut text)?;
+            // The message cannot end in the middle of a character
+            if frame.fin && incomplete.is_some() {
+                return Err(FrameError::InvalidUTF8);
+            }
+            Chunk::Text {
+                data: String::from_utf8(text).map_err(|_| FrameError::InvalidUTF8)?,
+                fin: frame.fin,
+            }
+        } else {
+            Chunk::Binary {
+                data: frame.data,
+                fin: frame.fin,
+            }
+        };
+        if !frame.fin {
+            self.fragments = Some(Fragment::Streamed(incomplete));
+        }
+        Ok(chunk)
+    }
 }
 
 impl Reader {
-    pub fn new(max_payload_size: usize) -> Self {
-        Self { max_payload_size, fragments: Fragments::new() }
+    /// A reader accepting frames and messages of up to `max_size` bytes.
+    pub fn new(role: Role, max_size: usize) -> Self {
+        Self::with_limits(
+            role,
+            Limits {
+                max_frame_size: max_size,
+                max_message_size: max_size,
+                ..Limits::default()
+            },
+        )
     }
 
+    pub fn with_limits(role: Role, limits: Limits) -> Self {
+        Self {
+            role,
+            limits,
+            fragments: Fragments::with_limits(limits),
+            extensions: Vec::new(),
+        }
+    }
+
+    pub fn add_extension(&mut self, extension: Box<dyn Extension>) {
+        self.extensions.push(extension);
+    }
+
+    /// Reads the next complete message, or a control frame interleaved with its fragments.
     pub async fn read(
         &mut self,
         reader: &mut (impl AsyncReadExt + Unpin),
-    ) -> Result<Frame, FrameError> {
+    ) -> Result<Message, FrameError> {
+        loop {
+            let frame = self.read_frame(reader).await?;
+
+            if let Some(res) = self.fragments.accumulate(frame)? {
+                return Message::from_validated(self.decode(res)?);
+            }
+        }
+    }
+
+    /// Reads the next piece of a message, handing out data frames as they arrive.
+    ///
+    /// Nothing is buffered, so only `max_frame_size` of the [`Limits`] applies. Messages an
+    /// extension has to decode, such as compressed ones, still come whole as
+    /// [`Chunk::Message`]. Mixing this with [`Reader::read`] within a message fails it.
+    pub async fn read_chunk(
+        &mut self,
+        reader: &mut (impl AsyncReadExt + Unpin),
+    ) -> Result<Chunk, FrameError> {
         loop {
             let frame = self.read_frame(reader).await?;
+            if self.fragments.streams(&frame) {
+                return self.fragments.stream(frame);
+            }
 
             if let Some(res) = self.fragments.accumulate(frame)? {
-                return Ok(res)
+                return Message::from_validated(self.decode(res)?).map(Chunk::Message);
             }
         }
     }
 
+    fn decode(&mut self, frame: Frame) -> Result<Frame, FrameError> {
+        let encoded = frame.rsv != 0;
+
+        // Decoders run in the reverse order of the encoders on the sending side
+        let mut frame = frame;
+        for extension in self.extensions.iter_mut().rev() {
+            frame = extension.decode(frame, self.limits.max_message_size)?;
+        }
+
+        if frame.rsv != 0 {
+            return Err(FrameError::ReservedBitsNotZero);
+        }
+        if encoded
+            && frame.opcode == Opcode::Text
+            && simdutf8::basic::from_utf8(&frame.data).is_err()
+        {
+            return Err(FrameError::InvalidUTF8);
+        }
+
+        Ok(frame)
+    }
+
+    /// Reads and validates a single frame without any reassembly.
     pub async fn read_frame(
         &self,
         reader: &mut (impl AsyncReadExt + Unpin),
     ) -> Result<Frame, FrameError> {
-
-        let mut payload: Vec<u8> = vec![];
         let mut buf = [0; 2];
         reader.read_exact(&mut buf).await?;
 
         let fin = buf[0] & 0b1000_0000 != 0;
-        let rsv1 = buf[0] & 0b0100_0000 != 0;
-        let rsv2 = buf[0] & 0b0010_0000 != 0;
-        let rsv3 = buf[0] & 0b0001_0000 != 0;
-        if rsv1 || rsv2 || rsv3 {
+        let rsv = buf[0] & (RSV1 | RSV2 | RSV3);
+        let claimed = self
+            .extensions
+            .iter()
+            .fold(0, |bits, extension| bits | extension.rsv_bits());
+        if rsv & !claimed != 0 {
             return Err(FrameError::ReservedBitsNotZero);
         }
         let opcode = Opcode::try_from(buf[0] & 0b0000_1111)?;
 
+        if let Opcode::Reserved(code) = opcode {
+            if !self
+                .extensions
+                .iter()
+                .any(|extension| extension.opcodes().contains(&code))
+            {
+                return Err(FrameError::InvalidOpCode(code));
+            }
+        }
+
+        // Extension bits mark the first frame of a message only
+        if rsv != 0
+            && matches!(
+                opcode,
+                Opcode::Continuation | Opcode::Close | Opcode::Ping | Opcode::Pong
+            )
+        {
+            return Err(FrameError::ReservedBitsNotZero);
+        }
+
         if opcode.is_control() && !fin {
-            return Err(FrameError::InvalidControlFin(opcode as u8));
+            return Err(FrameError::InvalidControlFin(opcode.into()));
         }
 
         // } else if opcode == Opcode::Continuation && is_first_frame {
@@ -173,51 +439,68 @@ impl Reader {
         //     return Err(FrameError::InvalidContinuation(opcode as u8));
         // }
 
+        // Clients must mask every frame, servers must never mask
         let mask = buf[1] & 0b1000_0000 != 0;
+        match self.role {
+            Role::Server if !mask => return Err(FrameError::UnmaskedFrame),
+            Role::Client if mask => return Err(FrameError::MaskedFrame),
+            _ => {}
+        }
+
+        // The length must use the minimal encoding
         let payload_len = match buf[1] & 0b0111_1111 {
             126 => {
                 let mut len_buf = [0; 2];
                 reader.read_exact(&mut len_buf).await?;
-                u16::from_be_bytes(len_buf) as u64
+                let len = u16::from_be_bytes(len_buf) as u64;
+                if len <= 125 {
+                    return Err(FrameError::InvalidPayloadLength(len));
+                }
+                len
             }
             127 => {
                 let mut len_buf = [0; 8];
                 reader.read_exact(&mut len_buf).await?;
-                u64::from_be_bytes(len_buf)
+                let len = u64::from_be_bytes(len_buf);
+                // The most significant bit must be 0
+                if len & (1 << 63) != 0 || len <= u16::MAX as u64 {
+                    return Err(FrameError::InvalidPayloadLength(len));
+                }
+                len
             }
             v => v as u64,
         };
 
-        if opcode == Opcode::Ping && payload_len > 125 {
-            return Err(FrameError::PingFrameTooLarge);
+        if opcode.is_control() && payload_len > 125 {
+            return Err(FrameError::ControlFrameTooLarge);
         }
 
-        let mut cur_payload = vec![0; payload_len as usize];
+        // Checked before anything is allocated, the length is still only a claim
+        if payload_len > self.limits.max_frame_size as u64 {
+            return Err(FrameError::FrameTooLarge);
+        }
+        if opcode == Opcode::Close && payload_len == 1 {
+            return Err(FrameError::InvalidCloseFrame);
+        }
 
+        let mut mask_key = [0; 4];
         if mask {
-            let mut mask_key = [0; 4];
             reader.read_exact(&mut mask_key).await?;
-            reader.read_exact(&mut cur_payload).await?;
-            for i in 0..cur_payload.len() {
-                cur_payload[i] ^= mask_key[i % 4];
-            }
-        } else {
-            reader.read_exact(&mut cur_payload).await?;
         }
-
-        payload.extend(cur_payload);
-
-        if opcode == Opcode::Close && payload.len() == 1{
-            return  Err(FrameError::InvalidCloseFrame);
+        let capacity = (payload_len as usize).min(INITIAL_PAYLOAD_CAPACITY);
+        let mut payload = Vec::with_capacity(capacity);
+        reader.take(payload_len).read_to_end(&mut payload).await?;
+        if payload.len() as u64 != payload_len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
         }
-
-        if payload.len() > self.max_payload_size {
-            return Err(FrameError::FrameTooLarge);
+        if mask {
+            apply_mask(&mut payload, mask_key);
         }
 
         Ok(Frame {
-            fin: fin,
-            opcode: opcode,
+            fin,
+            rsv,
+            opcode,
             len: payload.len(),
             data: payload,
         })
@@ -227,8 +510,28 @@ impl Reader {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::deflate::Inflater;
+    use crate::frame::CloseCode;
     use std::io::Cursor;
-    use tokio::io::AsyncReadExt;
+
+    struct Tagged;
+
+    impl Extension for Tagged {
+        fn rsv_bits(&self) -> u8 {
+            RSV3
+        }
+
+        fn opcodes(&self) -> &[u8] {
+            &[0x3]
+        }
+
+        fn decode(&mut self, mut frame: Frame, _max_size: usize) -> Result<Frame, FrameError> {
+            frame.rsv &= !RSV3;
+            frame.data.reverse();
+            frame.len = frame.data.len();
+            Ok(frame)
+        }
+    }
 
     #[tokio::test]
     async fn test_read_individual_frames() {
@@ -237,7 +540,7 @@ mod tests {
         // 2. Pong frame: "pong"
         // 3. Continuation frame: " World" with fin=true
         let mut test_data = Vec::new();
-        
+
         // Text frame: "Hello" (fin=false)
         test_data.extend_from_slice(&[
             0b0000_0001, // fin=0, rsv=0, opcode=1 (text)
@@ -260,7 +563,7 @@ mod tests {
         // test_data.extend_from_slice(b" World");
 
         let mut cursor = Cursor::new(test_data);
-        let frame_reader = Reader::new(1024);
+        let frame_reader = Reader::new(Role::Client, 1024);
 
         // Read first frame (text)
         let frame = frame_reader.read_frame(&mut cursor).await.unwrap();
@@ -287,7 +590,7 @@ mod tests {
         // 1. Text frame: "Hello" (fin=false)
         // 2. Continuation frame: " World" (fin=true)
         let mut test_data = Vec::new();
-        
+
         // Text frame: "Hello" (fin=false)
         test_data.extend_from_slice(&[
             0b0000_0001, // fin=0, rsv=0, opcode=1 (text)
@@ -310,17 +613,471 @@ mod tests {
         test_data.extend_from_slice(b" World");
 
         let mut cursor = Cursor::new(test_data);
-        let mut frame_reader = Reader::new(1024);
+        let mut frame_reader = Reader::new(Role::Client, 1024);
 
         // Read the pong message
-        let frame = frame_reader.read(&mut cursor).await.unwrap();
-        assert_eq!(frame.opcode, Opcode::Pong);
-        assert_eq!(frame.data, b"pong");
-        assert!(frame.fin);
+        let message = frame_reader.read(&mut cursor).await.unwrap();
+        assert_eq!(message, Message::Pong(b"pong".to_vec()));
+
+        let message = frame_reader.read(&mut cursor).await.unwrap();
+        assert_eq!(message, Message::Text("Hello World".to_string()));
+    }
 
-        let frame = frame_reader.read(&mut cursor).await.unwrap();
+    #[tokio::test]
+    async fn test_read_compressed_message() {
+        // "Hello" compressed as in RFC 7692 section 7.2.3.1, split over two frames
+        let mut test_data = Vec::new();
+        test_data.extend_from_slice(&[
+            0b0100_0001, // fin=0, rsv1=1, opcode=1 (text)
+            0b0000_0011, // mask=0, payload_len=3
+        ]);
+        test_data.extend_from_slice(&[0xf2, 0x48, 0xcd]);
+        test_data.extend_from_slice(&[
+            0b1000_0000, // fin=1, rsv=0, opcode=0 (continuation)
+            0b0000_0100, // mask=0, payload_len=4
+        ]);
+        test_data.extend_from_slice(&[0xc9, 0xc9, 0x07, 0x00]);
+
+        let mut cursor = Cursor::new(test_data);
+        let mut frame_reader = Reader::new(Role::Client, 1024);
+        frame_reader.add_extension(Box::new(Inflater::new(false)));
+
+        let message = frame_reader.read(&mut cursor).await.unwrap();
+        assert_eq!(message, Message::Text("Hello".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_read_rsv1_without_deflate() {
+        let test_data = vec![
+            0b1100_0001, // fin=1, rsv1=1, opcode=1 (text)
+            0b0000_0000, // mask=0, payload_len=0
+        ];
+
+        let mut cursor = Cursor::new(test_data);
+        let frame_reader = Reader::new(Role::Client, 1024);
+        let result = frame_reader.read_frame(&mut cursor).await;
+        assert!(matches!(result, Err(FrameError::ReservedBitsNotZero)));
+    }
+
+    #[tokio::test]
+    async fn test_read_rsv1_on_control_frame() {
+        let test_data = vec![
+            0b1100_1001, // fin=1, rsv1=1, opcode=9 (ping)
+            0b0000_0000, // mask=0, payload_len=0
+        ];
+
+        let mut cursor = Cursor::new(test_data);
+        let mut frame_reader = Reader::new(Role::Client, 1024);
+        frame_reader.add_extension(Box::new(Inflater::new(false)));
+        let result = frame_reader.read_frame(&mut cursor).await;
+        assert!(matches!(result, Err(FrameError::ReservedBitsNotZero)));
+    }
+
+    #[tokio::test]
+    async fn test_read_reserved_opcode() {
+        let test_data = vec![
+            0b1000_0011, // fin=1, rsv=0, opcode=3 (reserved)
+            0b0000_0000, // mask=0, payload_len=0
+        ];
+
+        let mut cursor = Cursor::new(test_data);
+        let frame_reader = Reader::new(Role::Client, 1024);
+        let result = frame_reader.read_frame(&mut cursor).await;
+        assert!(matches!(result, Err(FrameError::InvalidOpCode(3))));
+    }
+
+    #[tokio::test]
+    async fn test_read_extension_claimed_bits_and_opcode() {
+        let mut test_data = Vec::new();
+        test_data.extend_from_slice(&[
+            0b1001_0001, // fin=1, rsv3=1, opcode=1 (text)
+            0b0000_0011, // mask=0, payload_len=3
+        ]);
+        test_data.extend_from_slice(b"cba");
+        test_data.extend_from_slice(&[
+            0b1000_0011, // fin=1, rsv=0, opcode=3 (reserved)
+            0b0000_0010, // mask=0, payload_len=2
+        ]);
+        test_data.extend_from_slice(b"ok");
+
+        let mut cursor = Cursor::new(test_data);
+        let mut frame_reader = Reader::new(Role::Client, 1024);
+        frame_reader.add_extension(Box::new(Tagged));
+
+        let message = frame_reader.read(&mut cursor).await.unwrap();
+        assert_eq!(message, Message::Text("abc".to_string()));
+
+        let message = frame_reader.read(&mut cursor).await.unwrap();
+        let Message::Frame(frame) = message else {
+            panic!("expected a raw frame, got {:?}", message);
+        };
+        assert_eq!(frame.opcode, Opcode::Reserved(0x3));
+        assert_eq!(frame.data, b"ko");
+    }
+
+    #[tokio::test]
+    async fn test_read_masked_frame() {
+        // RFC 6455 section 5.7, a single-frame masked text message
+        let test_data = vec![
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+
+        let mut cursor = Cursor::new(test_data.clone());
+        let frame_reader = Reader::new(Role::Server, 1024);
+        let frame = frame_reader.read_frame(&mut cursor).await.unwrap();
         assert_eq!(frame.opcode, Opcode::Text);
-        assert_eq!(frame.data, b"Hello World");
-        assert!(frame.fin);
+        assert_eq!(frame.data, b"Hello");
+
+        let mut cursor = Cursor::new(test_data);
+        let frame_reader = Reader::new(Role::Client, 1024);
+        let result = frame_reader.read_frame(&mut cursor).await;
+        assert!(matches!(result, Err(FrameError::MaskedFrame)));
+    }
+
+    #[tokio::test]
+    async fn test_read_unmasked_frame_as_server() {
+        let mut test_data = vec![
+            0b1000_0001, // fin=1, rsv=0, opcode=1 (text)
+            0b0000_0101, // mask=0, payload_len=5
+        ];
+        test_data.extend_from_slice(b"Hello");
+
+        let mut cursor = Cursor::new(test_data);
+        let frame_reader = Reader::new(Role::Server, 1024);
+        let result = frame_reader.read_frame(&mut cursor).await;
+        assert!(matches!(result, Err(FrameError::UnmaskedFrame)));
+    }
+
+    #[tokio::test]
+    async fn test_read_control_frame_too_large() {
+        for opcode in [0b1000_1000, 0b1000_1001, 0b1000_1010] {
+            let mut test_data = vec![
+                opcode,      // fin=1, rsv=0, opcode=close/ping/pong
+                0b0111_1110, // mask=0, payload_len=126
+            ];
+            test_data.extend_from_slice(&126u16.to_be_bytes());
+            test_data.extend_from_slice(&[0; 126]);
+
+            let mut cursor = Cursor::new(test_data);
+            let frame_reader = Reader::new(Role::Client, 1024);
+            let result = frame_reader.read_frame(&mut cursor).await;
+            assert!(matches!(result, Err(FrameError::ControlFrameTooLarge)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_non_minimal_length() {
+        let mut test_data = vec![
+            0b1000_0010, // fin=1, rsv=0, opcode=2 (binary)
+            0b0111_1110, // mask=0, payload_len=126
+        ];
+        test_data.extend_from_slice(&5u16.to_be_bytes());
+        test_data.extend_from_slice(&[0; 5]);
+
+        let mut cursor = Cursor::new(test_data);
+        let frame_reader = Reader::new(Role::Client, 1024);
+        let result = frame_reader.read_frame(&mut cursor).await;
+        assert!(matches!(result, Err(FrameError::InvalidPayloadLength(5))));
+
+        let mut test_data = vec![
+            0b1000_0010, // fin=1, rsv=0, opcode=2 (binary)
+            0b0111_1111, // mask=0, payload_len=127
+        ];
+        test_data.extend_from_slice(&200u64.to_be_bytes());
+        test_data.extend_from_slice(&[0; 200]);
+
+        let mut cursor = Cursor::new(test_data);
+        let result = frame_reader.read_frame(&mut cursor).await;
+        assert!(matches!(result, Err(FrameError::InvalidPayloadLength(200))));
+    }
+
+    #[tokio::test]
+    async fn test_read_length_most_significant_bit() {
+        let mut test_data = vec![
+            0b1000_0010, // fin=1, rsv=0, opcode=2 (binary)
+            0b0111_1111, // mask=0, payload_len=127
+        ];
+        test_data.extend_from_slice(&(1u64 << 63).to_be_bytes());
+
+        let mut cursor = Cursor::new(test_data);
+        let frame_reader = Reader::new(Role::Client, 1024);
+        let result = frame_reader.read_frame(&mut cursor).await;
+        assert!(matches!(result, Err(FrameError::InvalidPayloadLength(_))));
+    }
+
+    #[tokio::test]
+    async fn test_read_new_message_during_fragment() {
+        let mut test_data = Vec::new();
+        test_data.extend_from_slice(&[
+            0b0000_0001, // fin=0, rsv=0, opcode=1 (text)
+            0b0000_0001, // mask=0, payload_len=1
+            b'a',
+            0b0000_0010, // fin=0, rsv=0, opcode=2 (binary)
+            0b0000_0001, // mask=0, payload_len=1
+            b'b',
+        ]);
+
+        let mut cursor = Cursor::new(test_data);
+        let mut frame_reader = Reader::new(Role::Client, 1024);
+        let result = frame_reader.read(&mut cursor).await;
+        assert!(matches!(result, Err(FrameError::InvalidFragment)));
+    }
+
+    #[tokio::test]
+    async fn test_read_text_ending_mid_character() {
+        let mut test_data = Vec::new();
+        test_data.extend_from_slice(&[
+            0b0000_0001, // fin=0, rsv=0, opcode=1 (text)
+            0b0000_0001, // mask=0, payload_len=1
+            b'a',
+            0b1000_0000, // fin=1, rsv=0, opcode=0 (continuation)
+            0b0000_0010, // mask=0, payload_len=2
+            0xe2,        // first two bytes of a three byte character
+            0x82,
+        ]);
+
+        let mut cursor = Cursor::new(test_data);
+        let mut frame_reader = Reader::new(Role::Client, 1024);
+        let result = frame_reader.read(&mut cursor).await;
+        assert!(matches!(result, Err(FrameError::InvalidUTF8)));
+    }
+
+    #[tokio::test]
+    async fn test_read_frame_too_large_close_code() {
+        let mut test_data = vec![
+            0b1000_0010, // fin=1, rsv=0, opcode=2 (binary)
+            0b0000_0101, // mask=0, payload_len=5
+        ];
+        test_data.extend_from_slice(&[0; 5]);
+
+        let mut cursor = Cursor::new(test_data);
+        let frame_reader = Reader::new(Role::Client, 4);
+        let error = frame_reader.read_frame(&mut cursor).await.unwrap_err();
+        assert!(matches!(error, FrameError::FrameTooLarge));
+        assert_eq!(error.close_code(), Some(CloseCode::Size));
+    }
+
+    /// A masked Binary frame header claiming `len` bytes, with nothing behind it.
+    fn hostile_header(len: u64) -> Cursor<Vec<u8>> {
+        let mut header = vec![0b1000_0010, 0b1000_0000 | 127];
+        header.extend_from_slice(&len.to_be_bytes());
+        header.extend_from_slice(&[1, 2, 3, 4]);
+        Cursor::new(header)
+    }
+
+    #[tokio::test]
+    async fn test_read_frame_huge_length_rejected_before_allocating() {
+        let frame_reader = Reader::new(Role::Server, DEFAULT_MAX_FRAME_SIZE);
+        let result = frame_reader
+            .read_frame(&mut hostile_header((1 << 63) - 1))
+            .await;
+        assert!(matches!(result, Err(FrameError::FrameTooLarge)));
+
+        // The most significant bit is not allowed at all
+        let result = frame_reader.read_frame(&mut hostile_header(1 << 63)).await;
+        assert!(matches!(result, Err(FrameError::InvalidPayloadLength(_))));
+    }
+
+    #[tokio::test]
+    async fn test_read_frame_grows_with_data() {
+        // Even without a limit, a claimed length alone allocates nothing near it
+        let frame_reader = Reader::new(Role::Server, usize::MAX);
+        let mut cursor = hostile_header(1 << 62);
+        cursor.get_mut().extend_from_slice(&[0; 100]);
+        let result = frame_reader.read_frame(&mut cursor).await;
+        assert!(matches!(
+            result,
+            Err(FrameError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof
+        ));
+
+        // Payloads beyond the initial capacity still arrive whole
+        let data: Vec<u8> = (0..3 * INITIAL_PAYLOAD_CAPACITY).map(|i| i as u8).collect();
+        let mut cursor = encode(&[Frame::new(Opcode::Binary, data.clone())]).await;
+        let frame_reader = Reader::new(Role::Client, DEFAULT_MAX_FRAME_SIZE);
+        assert_eq!(
+            frame_reader.read_frame(&mut cursor).await.unwrap().data,
+            data
+        );
+    }
+
+    fn fragment(opcode: Opcode, data: &[u8], fin: bool) -> Frame {
+        Frame {
+            fin,
+            ..Frame::new(opcode, data.to_vec())
+        }
+    }
+
+    #[test]
+    fn test_fragments_message_too_large() {
+        let mut fragments = Fragments::with_limits(Limits {
+            max_frame_size: 4,
+            max_message_size: 6,
+            ..Limits::default()
+        });
+        let error = fragments
+            .accumulate(fragment(Opcode::Binary, b"1234567", true))
+            .unwrap_err();
+        assert!(matches!(error, FrameError::MessageTooLarge));
+        assert_eq!(error.close_code(), Some(CloseCode::Size));
+
+        assert!(fragments
+            .accumulate(fragment(Opcode::Text, b"1234", false))
+            .unwrap()
+            .is_none());
+        assert!(fragments
+            .accumulate(fragment(Opcode::Continuation, b"56", false))
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            fragments.accumulate(fragment(Opcode::Continuation, b"7", true)),
+            Err(FrameError::MessageTooLarge)
+        ));
+    }
+
+    #[test]
+    fn test_fragments_too_many() {
+        let mut fragments = Fragments::with_limits(Limits {
+            max_fragments: 3,
+            ..Limits::default()
+        });
+
+        // Exactly at the limit, which applies to each message on its own
+        for _ in 0..2 {
+            fragments
+                .accumulate(fragment(Opcode::Binary, b"a", false))
+                .unwrap();
+            fragments
+                .accumulate(fragment(Opcode::Continuation, b"b", false))
+                .unwrap();
+            let message = fragments
+                .accumulate(fragment(Opcode::Continuation, b"c", true))
+                .unwrap()
+                .unwrap();
+            assert_eq!(message.data, b"abc");
+        }
+
+        fragments
+            .accumulate(fragment(Opcode::Binary, b"", false))
+            .unwrap();
+        for _ in 0..2 {
+            fragments
+                .accumulate(fragment(Opcode::Continuation, b"", false))
+                .unwrap();
+        }
+        let error = fragments
+            .accumulate(fragment(Opcode::Continuation, b"", false))
+            .unwrap_err();
+        assert!(matches!(error, FrameError::TooManyFragments));
+        assert_eq!(error.close_code(), Some(CloseCode::Size));
+    }
+
+    async fn encode(frames: &[Frame]) -> Cursor<Vec<u8>> {
+        let mut data = Vec::new();
+        for frame in frames {
+            crate::writer::Writer::write_frame(frame, &mut data)
+                .await
+                .unwrap();
+        }
+        Cursor::new(data)
+    }
+
+    #[tokio::test]
+    async fn test_read_chunks_split_character() {
+        // The euro sign is cut in two by the frame boundary, with a Ping in between
+        let mut cursor = encode(&[
+            fragment(Opcode::Text, &[b'a', 0xe2, 0x82], false),
+            fragment(Opcode::Ping, b"ping", true),
+            fragment(Opcode::Continuation, &[0xac, b'b'], true),
+            fragment(Opcode::Binary, &[1, 2], false),
+            fragment(Opcode::Continuation, &[3], true),
+        ])
+        .await;
+        let mut frame_reader = Reader::new(Role::Client, 1024);
+
+        let mut chunks = Vec::new();
+        for _ in 0..5 {
+            chunks.push(frame_reader.read_chunk(&mut cursor).await.unwrap());
+        }
+        assert_eq!(
+            chunks,
+            vec![
+                Chunk::Text {
+                    data: "a".to_string(),
+                    fin: false
+                },
+                Chunk::Message(Message::Ping(b"ping".to_vec())),
+                Chunk::Text {
+                    data: "\u{20ac}b".to_string(),
+                    fin: true
+                },
+                Chunk::Binary {
+                    data: vec![1, 2],
+                    fin: false
+                },
+                Chunk::Binary {
+                    data: vec![3],
+                    fin: true
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_chunks_invalid_utf8() {
+        let mut cursor = encode(&[
+            fragment(Opcode::Text, b"valid", false),
+            fragment(Opcode::Continuation, &[0xff], true),
+        ])
+        .await;
+        let mut frame_reader = Reader::new(Role::Client, 1024);
+
+        frame_reader.read_chunk(&mut cursor).await.unwrap();
+        assert!(matches!(
+            frame_reader.read_chunk(&mut cursor).await,
+            Err(FrameError::InvalidUTF8)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_chunks_limits() {
+        let mut cursor = encode(&[
+            fragment(Opcode::Binary, b"1234", false),
+            fragment(Opcode::Continuation, b"5678", true),
+            fragment(Opcode::Binary, b"12345", true),
+        ])
+        .await;
+        let mut frame_reader = Reader::with_limits(
+            Role::Client,
+            Limits {
+                max_frame_size: 4,
+                max_message_size: 4,
+                max_fragments: 1,
+            },
+        );
+
+        // Streamed messages are not buffered, frames still are
+        for _ in 0..2 {
+            frame_reader.read_chunk(&mut cursor).await.unwrap();
+        }
+        assert!(matches!(
+            frame_reader.read_chunk(&mut cursor).await,
+            Err(FrameError::FrameTooLarge)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_chunks_extension_message() {
+        let mut cursor = encode(&[Frame {
+            rsv: RSV3,
+            ..Frame::new(Opcode::Text, b"cba".to_vec())
+        }])
+        .await;
+        let mut frame_reader = Reader::new(Role::Client, 1024);
+        frame_reader.add_extension(Box::new(Tagged));
+
+        assert_eq!(
+            frame_reader.read_chunk(&mut cursor).await.unwrap(),
+            Chunk::Message(Message::Text("abc".to_string()))
+        );
     }
 }
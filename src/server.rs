@@ -0,0 +1,327 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::handler::{serve_with_deadline, Handler, ServerConfig};
+use crate::handshake::Peer;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout, timeout_at, Instant};
+
+/// Pause after an accept error such as EMFILE, which would otherwise fail again at once.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Tells an accept loop and its connections to shut down, cheap to clone.
+#[derive(Clone)]
+pub struct Shutdown {
+    signal: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self {
+            signal: Arc::new(watch::Sender::new(false)),
+        }
+    }
+
+    pub fn trigger(&self) {
+        self.signal.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.signal.borrow()
+    }
+
+    /// Completes once [`Shutdown::trigger`] was called.
+    pub async fn wait(&self) {
+        let mut triggered = self.signal.subscribe();
+        // The sender lives in `self`, so this cannot fail
+        let _ = triggered.wait_for(|triggered| *triggered).await;
+    }
+}
+
+/// Accepts connections until `shutdown` is triggered, giving each one a fresh handler.
+///
+/// On shutdown the listener is dropped and every live connection is closed with 1001 Going
+/// Away. Connections still open after [`ServerConfig::drain_timeout`] are aborted.
+pub async fn run<H: Handler>(
+    listener: TcpListener,
+    config: ServerConfig,
+    new_handler: impl Fn() -> H,
+    shutdown: Shutdown,
+) -> io::Result<()> {
+    accept_loop(
+        listener,
+        config,
+        new_handler,
+        shutdown,
+        |stream, addr| async move {
+            let peer = Peer {
+                addr: Some(addr),
+                ..Peer::default()
+            };
+            Ok((stream, peer))
+        },
+    )
+    .await
+}
+
+/// Like [`run`], but every connection is wrapped in TLS by `acceptor` first.
+///
+/// Pass a [`ReloadableAcceptor`](crate::tls::ReloadableAcceptor) to change certificates
+/// without a restart.
+#[cfg(feature = "tls")]
+pub async fn run_tls<H: Handler>(
+    listener: TcpListener,
+    acceptor: impl Into<crate::tls::ReloadableAcceptor>,
+    config: ServerConfig,
+    new_handler: impl Fn() -> H,
+    shutdown: Shutdown,
+) -> io::Result<()> {
+    let acceptor = acceptor.into();
+    accept_loop(
+        listener,
+        config,
+        new_handler,
+        shutdown,
+        move |stream, addr| {
+            let accept = acceptor.current().accept(stream);
+            async move {
+                let stream = accept.await?;
+                let peer = Peer {
+                    addr: Some(addr),
+                    certificate_subject: crate::tls::peer_subject(stream.get_ref().1),
+                };
+                Ok((stream, peer))
+            }
+        },
+    )
+    .await
+}
+
+/// Runs the accept loop, `upgrade` turns each TCP stream into the transport to serve and
+/// tells what it knows about the peer.
+async fn accept_loop<H, S, F>(
+    listener: TcpListener,
+    config: ServerConfig,
+    new_handler: impl Fn() -> H,
+    shutdown: Shutdown,
+    upgrade: impl Fn(TcpStream, SocketAddr) -> F,
+) -> io::Result<()>
+where
+    H: Handler,
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    F: Future<Output = io::Result<(S, Peer)>> + Send + 'static,
+{
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                // One deadline covers the TLS and the WebSocket handshake together
+                let deadline = Instant::now() + config.handshake.limits.timeout;
+                let (stream, addr) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        // Returning would drop every live connection without a Close
+                        log::warn!("Failed to accept connection: {}", e);
+                        if !is_connection_error(&e) {
+                            // Likely out of file descriptors, give connections time to finish
+                            tokio::select! {
+                                _ = sleep(ACCEPT_BACKOFF) => {}
+                                _ = shutdown.wait() => break,
+                            }
+                        }
+                        continue;
+                    }
+                };
+                let upgrade = upgrade(stream, addr);
+                let config = config.clone();
+                let handler = new_handler();
+                let shutdown = shutdown.clone();
+
+                connections.spawn(async move {
+                    let upgraded = timeout_at(deadline, upgrade)
+                        .await
+                        .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+                    let (stream, peer) = match upgraded {
+                        Ok(upgraded) => upgraded,
+                        Err(e) => {
+                            log::warn!("Failed to set up connection: {}", e);
+                            return;
+                        }
+                    };
+                    if let Err(e) = serve_with_deadline(stream, peer, &config, handler, shutdown.wait(), deadline).await {
+                        log::warn!("Handshake failed: {}", e);
+                    }
+                });
+            }
+            // Finished connections are reaped so the set does not grow forever
+            Some(_) = connections.join_next() => {}
+            _ = shutdown.wait() => break,
+        }
+    }
+    drop(listener);
+
+    let drained = async { while connections.join_next().await.is_some() {} };
+    if timeout(config.drain_timeout, drained).await.is_err() {
+        connections.shutdown().await;
+    }
+    Ok(())
+}
+
+/// Errors that only concern the connection being accepted, the next accept may succeed.
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::Interrupted
+    )
+}
+
+/// Completes on the first SIGINT or SIGTERM.
+pub async fn shutdown_signal() -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, ClientConfig};
+    use crate::frame::CloseCode;
+    use crate::handler::Echo;
+    use crate::handshake::{HandshakeConfig, HandshakeLimits};
+    use crate::message::{CloseFrame, Message};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::task::JoinHandle;
+
+    async fn start(config: ServerConfig) -> (String, Shutdown, JoinHandle<io::Result<()>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let shutdown = Shutdown::new();
+        let server = tokio::spawn(run(listener, config, || Echo, shutdown.clone()));
+        (addr, shutdown, server)
+    }
+
+    fn going_away() -> Message {
+        Message::Close(Some(CloseFrame {
+            code: CloseCode::Away,
+            reason: "Server shutting down".to_string(),
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_closes_connections() {
+        let (addr, shutdown, server) = start(ServerConfig::default()).await;
+        let mut client = Client::connect(&addr, "/", &ClientConfig::default())
+            .await
+            .unwrap();
+        client
+            .write(Message::Text("Hello".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(
+            client.read().await.unwrap(),
+            Message::Text("Hello".to_string())
+        );
+
+        shutdown.trigger();
+        assert_eq!(client.read().await.unwrap(), going_away());
+        client.write(going_away()).await.unwrap();
+        server.await.unwrap().unwrap();
+
+        // The listener is gone
+        assert!(Client::connect(&addr, "/", &ClientConfig::default())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drain_timeout() {
+        let config = ServerConfig {
+            close_timeout: Duration::from_secs(60),
+            drain_timeout: Duration::from_millis(50),
+            ..ServerConfig::default()
+        };
+        let (addr, shutdown, server) = start(config).await;
+        let mut client = Client::connect(&addr, "/", &ClientConfig::default())
+            .await
+            .unwrap();
+        client.write(Message::Ping(Vec::new())).await.unwrap();
+        assert_eq!(client.read().await.unwrap(), Message::Pong(Vec::new()));
+
+        // A client that never answers the Close does not hold the server up
+        shutdown.trigger();
+        assert_eq!(client.read().await.unwrap(), going_away());
+        server.await.unwrap().unwrap();
+        assert!(client.read().await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_handshake_deadline_covers_upgrade() {
+        let config = ServerConfig {
+            handshake: HandshakeConfig {
+                limits: HandshakeLimits {
+                    timeout: Duration::from_secs(10),
+                    ..HandshakeLimits::default()
+                },
+                ..HandshakeConfig::default()
+            },
+            ..ServerConfig::default()
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = Shutdown::new();
+        // Stands in for a TLS handshake the client drags out
+        let upgrade = |stream, addr| async move {
+            sleep(Duration::from_secs(8)).await;
+            let peer = Peer {
+                addr: Some(addr),
+                ..Peer::default()
+            };
+            Ok((stream, peer))
+        };
+        let server = tokio::spawn(accept_loop(
+            listener,
+            config,
+            || Echo,
+            shutdown.clone(),
+            upgrade,
+        ));
+
+        // Then stalls in the middle of its request
+        let started = Instant::now();
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        let mut response = [0; 64];
+        let read = stream.read(&mut response).await.unwrap();
+        assert!(response[..read].starts_with(b"HTTP/1.1 408 "));
+        assert!(started.elapsed() < Duration::from_secs(11));
+
+        shutdown.trigger();
+        server.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,716 @@
+use std::sync::Arc;
+
+use crate::frame::{CloseCode, FrameError, Opcode};
+use crate::message::{CloseFrame, Message};
+use crate::reader::{Chunk, Reader};
+use crate::writer::{StreamedFrames, Writer};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot, watch};
+
+/// Messages waiting for the write task before senders are held back.
+const WRITE_QUEUE_SIZE: usize = 32;
+
+/// Progress of the closing handshake, see RFC 6455 section 7.1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseState {
+    Open,
+    /// Our Close frame was written, the peer's is still expected.
+    CloseSent,
+    /// The peer's Close frame arrived, ours is still to be written.
+    CloseReceived,
+    /// Both Close frames went through, only the TCP connection is left to close.
+    Closed,
+}
+
+impl CloseState {
+    pub fn is_close_sent(self) -> bool {
+        matches!(self, CloseState::CloseSent | CloseState::Closed)
+    }
+
+    pub fn is_close_received(self) -> bool {
+        matches!(self, CloseState::CloseReceived | CloseState::Closed)
+    }
+
+    fn sent(self) -> Self {
+        match self {
+            CloseState::CloseReceived => CloseState::Closed,
+            CloseState::Closed => CloseState::Closed,
+            _ => CloseState::CloseSent,
+        }
+    }
+
+    fn received(self) -> Self {
+        match self {
+            CloseState::CloseSent => CloseState::Closed,
+            CloseState::Closed => CloseState::Closed,
+            _ => CloseState::CloseReceived,
+        }
+    }
+}
+
+/// A message streamed from a reader, see [`Writer::write_from`].
+struct Stream {
+    frames: StreamedFrames,
+    source: Box<dyn AsyncRead + Send + Unpin>,
+}
+
+enum Outgoing {
+    Message(Message),
+    Stream(Stream),
+}
+
+enum Command {
+    Send(Outgoing, oneshot::Sender<Result<(), FrameError>>),
+    Shutdown(oneshot::Sender<()>),
+}
+
+/// Receiving side of a split connection.
+pub struct WsReadHalf<R> {
+    read_half: R,
+    reader: Reader,
+    // Written on behalf of the read half, nobody waits for the result
+    replies: mpsc::Sender<Message>,
+    state: Arc<watch::Sender<CloseState>>,
+}
+
+/// Sending side of a split connection, cheap to clone into other tasks.
+#[derive(Clone)]
+pub struct WsWriteHalf {
+    commands: mpsc::Sender<Command>,
+    state: Arc<watch::Sender<CloseState>>,
+}
+
+/// Splits an upgraded connection into halves that can be moved into different tasks.
+///
+/// The write half is driven by a task spawned on the current runtime, which also writes the
+/// pongs and Close replies the read half asks for. Those go out ahead of queued messages,
+/// and between the frames of a fragmented one, see [`Writer::set_fragment_size`]. The task
+/// keeps `write_half` until [`WsWriteHalf::shutdown`] or until every handle to the
+/// connection is dropped.
+pub fn split<R, W>(
+    read_half: R,
+    write_half: W,
+    reader: Reader,
+    writer: Writer,
+) -> (WsReadHalf<R>, WsWriteHalf)
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let (commands, queue) = mpsc::channel(WRITE_QUEUE_SIZE);
+    let (replies, pending) = mpsc::channel(WRITE_QUEUE_SIZE);
+    let state = Arc::new(watch::Sender::new(CloseState::Open));
+    let task = WriteTask {
+        writer,
+        write_half,
+        replies: pending,
+        state: state.clone(),
+        started: false,
+    };
+    tokio::spawn(task.run(queue));
+    (
+        WsReadHalf {
+            read_half,
+            reader,
+            replies,
+            state: state.clone(),
+        },
+        WsWriteHalf { commands, state },
+    )
+}
+
+/// Owns the sending side of the transport on behalf of both halves.
+struct WriteTask<W> {
+    writer: Writer,
+    write_half: W,
+    replies: mpsc::Receiver<Message>,
+    state: Arc<watch::Sender<CloseState>>,
+    /// Whether a frame of the message being sent reached the transport.
+    started: bool,
+}
+
+impl<W> WriteTask<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    async fn run(mut self, mut queue: mpsc::Receiver<Command>) {
+        loop {
+            let command = tokio::select! {
+                biased;
+                Some(reply) = self.replies.recv() => {
+                    if self.reply(reply).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                Some(command) = queue.recv() => command,
+                else => break,
+            };
+
+            match command {
+                Command::Send(outgoing, result) => {
+                    self.started = false;
+                    let written = self.send(outgoing).await;
+                    // Refusing to write after a Close frame leaves the connection intact, and so
+                    // does a failure before any frame of the message was written
+                    let failed = self.started
+                        && matches!(&written, Err(e) if !matches!(e, FrameError::ConnectionClosed));
+                    let _ = result.send(written);
+                    if failed {
+                        break;
+                    }
+                }
+                Command::Shutdown(done) => {
+                    let _ = self.write_half.shutdown().await;
+                    let _ = done.send(());
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn send(&mut self, outgoing: Outgoing) -> Result<(), FrameError> {
+        self.check_open()?;
+        match outgoing {
+            Outgoing::Message(message) => {
+                let is_close = matches!(message, Message::Close(_));
+                for frame in self.writer.fragment(message)? {
+                    self.check_open()?;
+                    self.started = true;
+                    self.writer
+                        .write_encoded(&frame, &mut self.write_half)
+                        .await?;
+                    if !frame.fin {
+                        self.write_replies().await?;
+                    }
+                }
+                if is_close {
+                    self.state.send_modify(|state| *state = state.sent());
+                }
+            }
+            Outgoing::Stream(mut stream) => {
+                while let Some(frame) = stream.frames.next(&mut stream.source).await? {
+                    self.check_open()?;
+                    let fin = frame.fin;
+                    self.started = true;
+                    self.writer.send_frame(frame, &mut self.write_half).await?;
+                    if !fin {
+                        self.write_replies().await?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the replies queued so far, in the middle of a fragmented message.
+    async fn write_replies(&mut self) -> Result<(), FrameError> {
+        while let Ok(reply) = self.replies.try_recv() {
+            self.reply(reply).await?;
+        }
+        Ok(())
+    }
+
+    async fn reply(&mut self, reply: Message) -> Result<(), FrameError> {
+        if self.state.borrow().is_close_sent() {
+            return Ok(());
+        }
+        let is_close = matches!(reply, Message::Close(_));
+        self.writer.write(reply, &mut self.write_half).await?;
+        if is_close {
+            self.state.send_modify(|state| *state = state.sent());
+        }
+        Ok(())
+    }
+
+    // Nothing may follow a Close frame, not even the rest of a fragmented message
+    fn check_open(&self) -> Result<(), FrameError> {
+        if self.state.borrow().is_close_sent() {
+            return Err(FrameError::ConnectionClosed);
+        }
+        Ok(())
+    }
+}
+
+impl<R> WsReadHalf<R>
+where
+    R: AsyncBufRead + Unpin,
+{
+    /// Reads the next message, queueing the pong or Close reply it calls for.
+    ///
+    /// Protocol errors are answered with a Close frame carrying [`FrameError::close_code`].
+    /// Nothing is read after the peer's Close frame.
+    pub async fn read(&mut self) -> Result<Message, FrameError> {
+        if self.state().is_close_received() {
+            return Err(FrameError::ConnectionClosed);
+        }
+
+        let message = match self.reader.read(&mut self.read_half).await {
+            Ok(message) => message,
+            Err(e) => return Err(self.fail(e).await),
+        };
+        self.reply_to(&message).await;
+        Ok(message)
+    }
+
+    /// Like [`WsReadHalf::read`], but hands out data frames as they arrive, see
+    /// [`Reader::read_chunk`].
+    pub async fn read_chunk(&mut self) -> Result<Chunk, FrameError> {
+        if self.state().is_close_received() {
+            return Err(FrameError::ConnectionClosed);
+        }
+
+        let chunk = match self.reader.read_chunk(&mut self.read_half).await {
+            Ok(chunk) => chunk,
+            Err(e) => return Err(self.fail(e).await),
+        };
+        if let Chunk::Message(message) = &chunk {
+            self.reply_to(message).await;
+        }
+        Ok(chunk)
+    }
+
+    /// Fails the connection with the matching status, RFC 6455 section 7.1.7.
+    async fn fail(&mut self, e: FrameError) -> FrameError {
+        if let (Some(close), CloseState::Open) = (CloseFrame::from_error(&e), self.state()) {
+            let _ = self.replies.send(Message::Close(Some(close))).await;
+        }
+        e
+    }
+
+    async fn reply_to(&mut self, message: &Message) {
+        let reply = match message {
+            Message::Ping(data) => Some(Message::Pong(data.clone())),
+            Message::Close(close) => {
+                let mut was_open = false;
+                self.state.send_modify(|state| {
+                    was_open = *state == CloseState::Open;
+                    *state = state.received();
+                });
+                // An answer to our own Close needs no reply
+                was_open.then(|| Message::close_reply(close.clone()))
+            }
+            _ => None,
+        };
+        if let Some(reply) = reply {
+            // The write task is gone if writing failed, the next write reports it
+            let _ = self.replies.send(reply).await;
+        }
+    }
+
+    pub fn state(&self) -> CloseState {
+        *self.state.borrow()
+    }
+
+    pub(crate) fn watch_state(&self) -> watch::Receiver<CloseState> {
+        self.state.subscribe()
+    }
+}
+
+impl WsWriteHalf {
+    /// Writes a message once the replies queued before it went out.
+    pub async fn write(&self, message: Message) -> Result<(), FrameError> {
+        self.send(Outgoing::Message(message)).await
+    }
+
+    /// Streams `source` as one message, see [`Writer::write_from`].
+    ///
+    /// Other writes wait until the whole message went out. Failing to read `source` midway
+    /// leaves the message unfinished, so the connection is dropped.
+    pub async fn write_from(
+        &self,
+        opcode: Opcode,
+        source: impl AsyncRead + Send + Unpin + 'static,
+        frame_size: usize,
+    ) -> Result<(), FrameError> {
+        self.send(Outgoing::Stream(Stream {
+            frames: StreamedFrames::new(opcode, frame_size),
+            source: Box::new(source),
+        }))
+        .await
+    }
+
+    async fn send(&self, outgoing: Outgoing) -> Result<(), FrameError> {
+        let (result, written) = oneshot::channel();
+        self.commands
+            .send(Command::Send(outgoing, result))
+            .await
+            .map_err(|_| FrameError::ConnectionClosed)?;
+        written.await.map_err(|_| FrameError::ConnectionClosed)?
+    }
+
+    /// Starts the closing handshake, later writes fail with [`FrameError::ConnectionClosed`].
+    ///
+    /// Codes that may not be sent are rejected, a reason over 123 bytes is truncated.
+    pub async fn close(&self, code: CloseCode, reason: &str) -> Result<(), FrameError> {
+        if !code.is_allowed() {
+            return Err(FrameError::InvalidCloseFrame);
+        }
+        self.write(Message::Close(Some(CloseFrame::truncated(code, reason))))
+            .await
+    }
+
+    /// Shuts the transport down once everything queued before was written.
+    pub async fn shutdown(&self) {
+        let (done, shut) = oneshot::channel();
+        if self.commands.send(Command::Shutdown(done)).await.is_ok() {
+            let _ = shut.await;
+        }
+    }
+
+    pub fn state(&self) -> CloseState {
+        *self.state.borrow()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{Frame, Role};
+    use crate::reader::DEFAULT_MAX_MESSAGE_SIZE;
+    use tokio::io::{duplex, BufReader, DuplexStream, ReadHalf};
+
+    type Half = WsReadHalf<BufReader<ReadHalf<DuplexStream>>>;
+
+    fn halves(stream: DuplexStream, role: Role) -> (Half, WsWriteHalf) {
+        let (read_half, write_half) = tokio::io::split(stream);
+        split(
+            BufReader::new(read_half),
+            write_half,
+            Reader::new(role, DEFAULT_MAX_MESSAGE_SIZE),
+            Writer::new(role),
+        )
+    }
+
+    fn pair() -> ((Half, WsWriteHalf), (Half, WsWriteHalf)) {
+        let (server, client) = duplex(4096);
+        (halves(server, Role::Server), halves(client, Role::Client))
+    }
+
+    fn close(code: CloseCode) -> Message {
+        Message::Close(Some(CloseFrame {
+            code,
+            reason: String::new(),
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_split_halves_in_tasks() {
+        let ((mut server_read, server_write), (mut client_read, client_write)) = pair();
+
+        let sender = tokio::spawn(async move {
+            for i in 0..3 {
+                client_write
+                    .write(Message::Text(i.to_string()))
+                    .await
+                    .unwrap();
+            }
+        });
+        for i in 0..3 {
+            assert_eq!(
+                server_read.read().await.unwrap(),
+                Message::Text(i.to_string())
+            );
+        }
+        sender.await.unwrap();
+
+        server_write
+            .clone()
+            .write(Message::Binary(vec![1]))
+            .await
+            .unwrap();
+        assert_eq!(client_read.read().await.unwrap(), Message::Binary(vec![1]));
+    }
+
+    #[tokio::test]
+    async fn test_split_ping_reply() {
+        let ((mut server_read, _server_write), (mut client_read, client_write)) = pair();
+
+        client_write
+            .write(Message::Ping(b"hi".to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(
+            server_read.read().await.unwrap(),
+            Message::Ping(b"hi".to_vec())
+        );
+        assert_eq!(
+            client_read.read().await.unwrap(),
+            Message::Pong(b"hi".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_split_close_reply() {
+        let ((mut server_read, server_write), (mut client_read, client_write)) = pair();
+
+        client_write.write(close(CloseCode::Normal)).await.unwrap();
+        assert_eq!(server_read.read().await.unwrap(), close(CloseCode::Normal));
+        assert_eq!(client_read.read().await.unwrap(), close(CloseCode::Normal));
+
+        assert_eq!(client_read.state(), CloseState::Closed);
+        assert_eq!(server_write.state(), CloseState::Closed);
+
+        // Nothing is written or read after the Close frames
+        assert!(matches!(
+            client_read.read().await,
+            Err(FrameError::ConnectionClosed)
+        ));
+        assert!(matches!(
+            client_write.write(Message::Text("late".to_string())).await,
+            Err(FrameError::ConnectionClosed)
+        ));
+        assert!(matches!(
+            server_write.write(Message::Text("late".to_string())).await,
+            Err(FrameError::ConnectionClosed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_split_server_initiated_close() {
+        let ((mut server_read, server_write), (mut client_read, _client_write)) = pair();
+
+        server_write.close(CloseCode::Away, "bye").await.unwrap();
+        assert_eq!(server_write.state(), CloseState::CloseSent);
+        assert!(matches!(
+            server_write.write(Message::Text("late".to_string())).await,
+            Err(FrameError::ConnectionClosed)
+        ));
+
+        // The client's automatic reply completes the handshake
+        let bye = Message::Close(Some(CloseFrame {
+            code: CloseCode::Away,
+            reason: "bye".to_string(),
+        }));
+        assert_eq!(client_read.read().await.unwrap(), bye);
+        assert_eq!(server_read.read().await.unwrap(), bye);
+        assert_eq!(server_read.state(), CloseState::Closed);
+
+        server_write.shutdown().await;
+        let eof = client_read.reader.read(&mut client_read.read_half).await;
+        assert!(matches!(eof, Err(FrameError::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn test_split_close_checked() {
+        let ((_server_read, server_write), (mut client_read, _client_write)) = pair();
+
+        for code in [CloseCode::Status, CloseCode::Abnormal, CloseCode::Tls] {
+            assert!(matches!(
+                server_write.close(code, "").await,
+                Err(FrameError::InvalidCloseFrame)
+            ));
+        }
+        assert_eq!(server_write.state(), CloseState::Open);
+
+        server_write
+            .close(CloseCode::Away, &"a".repeat(200))
+            .await
+            .unwrap();
+        assert_eq!(
+            client_read.read().await.unwrap(),
+            Message::Close(Some(CloseFrame {
+                code: CloseCode::Away,
+                reason: "a".repeat(123),
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_split_protocol_error_close() {
+        let (server, client) = duplex(4096);
+        let (mut server_read, _server_write) = halves(server, Role::Server);
+        let (client_read, mut client_write) = tokio::io::split(client);
+
+        // A fragmented message cannot continue with a new one
+        let first = Frame {
+            fin: false,
+            ..Frame::new(Opcode::Text, b"a".to_vec())
+        };
+        let mut writer = Writer::new(Role::Client);
+        writer
+            .send_frame(first.clone(), &mut client_write)
+            .await
+            .unwrap();
+        writer.send_frame(first, &mut client_write).await.unwrap();
+        assert!(matches!(
+            server_read.read().await,
+            Err(FrameError::InvalidFragment)
+        ));
+
+        let mut reader = Reader::new(Role::Client, DEFAULT_MAX_MESSAGE_SIZE);
+        assert_eq!(
+            reader.read(&mut BufReader::new(client_read)).await.unwrap(),
+            Message::Close(Some(CloseFrame {
+                code: CloseCode::Protocol,
+                reason: "Invalid fragment".to_string(),
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_split_read_chunks() {
+        let (server, client) = duplex(4096);
+        let (mut server_read, _server_write) = halves(server, Role::Server);
+        let (client_read, mut client_write) = tokio::io::split(client);
+
+        let mut writer = Writer::new(Role::Client);
+        let frames = [
+            Frame {
+                fin: false,
+                ..Frame::new(Opcode::Binary, vec![1])
+            },
+            Frame::new(Opcode::Ping, b"hi".to_vec()),
+            Frame::new(Opcode::Continuation, vec![2]),
+        ];
+        for frame in frames {
+            writer.send_frame(frame, &mut client_write).await.unwrap();
+        }
+
+        assert_eq!(
+            server_read.read_chunk().await.unwrap(),
+            Chunk::Binary {
+                data: vec![1],
+                fin: false
+            }
+        );
+        assert_eq!(
+            server_read.read_chunk().await.unwrap(),
+            Chunk::Message(Message::Ping(b"hi".to_vec()))
+        );
+        assert_eq!(
+            server_read.read_chunk().await.unwrap(),
+            Chunk::Binary {
+                data: vec![2],
+                fin: true
+            }
+        );
+
+        // The Ping was answered while the message was still coming in
+        let mut reader = Reader::new(Role::Client, DEFAULT_MAX_MESSAGE_SIZE);
+        assert_eq!(
+            reader.read(&mut BufReader::new(client_read)).await.unwrap(),
+            Message::Pong(b"hi".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_split_write_from() {
+        let ((mut server_read, _server_write), (_client_read, client_write)) = pair();
+
+        let data: Vec<u8> = (0..20_000).map(|i| i as u8).collect();
+        let source = std::io::Cursor::new(data.clone());
+        let sender =
+            tokio::spawn(
+                async move { client_write.write_from(Opcode::Binary, source, 1024).await },
+            );
+        assert_eq!(server_read.read().await.unwrap(), Message::Binary(data));
+        sender.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_split_write_from_invalid_source() {
+        let ((mut server_read, _server_write), (_client_read, client_write)) = pair();
+
+        // Nothing was written yet, so the connection stays usable
+        let source = std::io::Cursor::new(vec![b'a', 0xff]);
+        assert!(matches!(
+            client_write.write_from(Opcode::Text, source, 1024).await,
+            Err(FrameError::InvalidUTF8)
+        ));
+        client_write
+            .write(Message::Text("ok".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(
+            server_read.read().await.unwrap(),
+            Message::Text("ok".to_string())
+        );
+    }
+
+    /// A server whose messages go out in frames of 1 KiB, and the raw client end.
+    fn fragmenting_server() -> (Half, WsWriteHalf, Half, WsWriteHalf) {
+        let (server, client) = duplex(4096);
+        let (read_half, write_half) = tokio::io::split(server);
+        let mut writer = Writer::new(Role::Server);
+        writer.set_fragment_size(Some(1024));
+        let (server_read, server_write) = split(
+            BufReader::new(read_half),
+            write_half,
+            Reader::new(Role::Server, DEFAULT_MAX_MESSAGE_SIZE),
+            writer,
+        );
+        let (client_read, client_write) = halves(client, Role::Client);
+        (server_read, server_write, client_read, client_write)
+    }
+
+    #[tokio::test]
+    async fn test_split_pong_between_fragments() {
+        let (mut server_read, server_write, mut client_read, client_write) = fragmenting_server();
+
+        // The transport fills up long before the message is written
+        let data = vec![7; 64 * 1024];
+        let message = Message::Binary(data.clone());
+        let sender = tokio::spawn(async move { server_write.write(message).await });
+        client_write
+            .write(Message::Ping(b"hi".to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(
+            server_read.read().await.unwrap(),
+            Message::Ping(b"hi".to_vec())
+        );
+
+        let mut opcodes = Vec::new();
+        let mut received = Vec::new();
+        loop {
+            let frame = client_read
+                .reader
+                .read_frame(&mut client_read.read_half)
+                .await
+                .unwrap();
+            opcodes.push(frame.opcode);
+            if frame.opcode == Opcode::Pong {
+                assert_eq!(frame.data, b"hi");
+                continue;
+            }
+            received.extend_from_slice(&frame.data);
+            if frame.fin {
+                break;
+            }
+        }
+        sender.await.unwrap().unwrap();
+        assert_eq!(received, data);
+        assert_eq!(opcodes.len(), 65);
+        let pong = opcodes.iter().position(|&op| op == Opcode::Pong).unwrap();
+        assert!(pong > 0 && pong < 64, "Pong waited for the message");
+    }
+
+    #[tokio::test]
+    async fn test_split_close_between_fragments() {
+        let (mut server_read, server_write, mut client_read, client_write) = fragmenting_server();
+
+        let message = Message::Binary(vec![7; 64 * 1024]);
+        let sender = tokio::spawn(async move { server_write.write(message).await });
+        client_write.write(close(CloseCode::Normal)).await.unwrap();
+        assert_eq!(server_read.read().await.unwrap(), close(CloseCode::Normal));
+
+        // The Close reply cuts the message short, nothing follows it
+        loop {
+            let frame = client_read
+                .reader
+                .read_frame(&mut client_read.read_half)
+                .await
+                .unwrap();
+            if frame.opcode == Opcode::Close {
+                break;
+            }
+            assert!(!frame.fin);
+        }
+        assert!(matches!(
+            sender.await.unwrap(),
+            Err(FrameError::ConnectionClosed)
+        ));
+        assert_eq!(server_read.state(), CloseState::Closed);
+    }
+}
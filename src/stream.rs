@@ -0,0 +1,504 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use crate::frame::{CloseCode, FrameError, Role};
+use crate::handshake::{do_handshake, HandshakeConfig, HandshakeError};
+use crate::message::{CloseFrame, Message};
+use crate::reader::{Limits, Reader};
+use crate::writer::Writer;
+use futures_core::Stream;
+use futures_sink::Sink;
+use tokio::io::{AsyncRead, AsyncWrite, BufReader, BufWriter, ReadHalf, WriteHalf};
+
+type ReadFuture<S> = Pin<
+    Box<dyn Future<Output = (Reader, BufReader<ReadHalf<S>>, Result<Message, FrameError>)> + Send>,
+>;
+type WriteFuture<S> =
+    Pin<Box<dyn Future<Output = (Writer, BufWriter<WriteHalf<S>>, Result<(), FrameError>)> + Send>>;
+
+enum ReadState<S> {
+    Idle(Reader, BufReader<ReadHalf<S>>),
+    Reading(ReadFuture<S>),
+    Done,
+}
+
+enum WriteState<S> {
+    Idle(Writer, BufWriter<WriteHalf<S>>),
+    Writing(WriteFuture<S>),
+    Done,
+}
+
+/// A connection exposed as a [`Stream`] of incoming messages and a [`Sink`] of outgoing ones.
+///
+/// Pings are answered and Close frames echoed internally, they are still yielded so the
+/// application can observe them. Protocol errors are answered with a Close frame carrying
+/// [`FrameError::close_code`]. The stream ends once a Close frame was received, or an error
+/// returned, and the reply, if any, was written.
+pub struct WebSocketStream<S> {
+    read: ReadState<S>,
+    write: WriteState<S>,
+    queue: VecDeque<Message>,
+    close_sent: bool,
+    close_received: bool,
+    protocol: Option<String>,
+}
+
+impl<S> WebSocketStream<S>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    /// Wraps a transport whose opening handshake is already done, without extensions.
+    pub fn new(stream: S, role: Role) -> Self {
+        Self::with_limits(stream, role, Limits::default())
+    }
+
+    /// Like [`WebSocketStream::new`], with caps on what the peer may send.
+    pub fn with_limits(stream: S, role: Role, limits: Limits) -> Self {
+        let (read_half, write_half) = tokio::io::split(stream);
+        Self::from_parts(
+            BufReader::new(read_half),
+            BufWriter::new(write_half),
+            Reader::with_limits(role, limits),
+            Writer::new(role),
+        )
+    }
+
+    /// Builds the stream from the halves used for the handshake, keeping anything the
+    /// buffered reader already holds.
+    pub fn from_parts(
+        read_half: BufReader<ReadHalf<S>>,
+        write_half: BufWriter<WriteHalf<S>>,
+        reader: Reader,
+        writer: Writer,
+    ) -> Self {
+        Self {
+            read: ReadState::Idle(reader, read_half),
+            write: WriteState::Idle(writer, write_half),
+            queue: VecDeque::new(),
+            close_sent: false,
+            close_received: false,
+            protocol: None,
+        }
+    }
+
+    /// Performs the server side of the opening handshake and wraps the upgraded transport.
+    pub async fn accept(stream: S, config: &HandshakeConfig) -> Result<Self, HandshakeError> {
+        Self::accept_with_limits(stream, config, Limits::default()).await
+    }
+
+    /// Like [`WebSocketStream::accept`], with caps on what the peer may send.
+    pub async fn accept_with_limits(
+        stream: S,
+        config: &HandshakeConfig,
+        limits: Limits,
+    ) -> Result<Self, HandshakeError> {
+        let (read_half, write_half) = tokio::io::split(stream);
+        let mut read_half = BufReader::new(read_half);
+        let mut write_half = BufWriter::new(write_half);
+        let handshake = do_handshake(&mut read_half, &mut write_half, config).await?;
+
+        let mut reader = Reader::with_limits(Role::Server, limits);
+        let mut writer = Writer::new(Role::Server);
+        for extension in handshake.extensions {
+            reader.add_extension(extension.decoder);
+            writer.add_extension(extension.encoder);
+        }
+
+        let mut stream = Self::from_parts(read_half, write_half, reader, writer);
+        stream.protocol = handshake.protocol;
+        Ok(stream)
+    }
+
+    /// The subprotocol selected during the handshake.
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    /// Writes queued messages until none are left.
+    fn poll_write_queue(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), FrameError>> {
+        loop {
+            match mem::replace(&mut self.write, WriteState::Done) {
+                WriteState::Idle(mut writer, mut write_half) => match self.queue.pop_front() {
+                    Some(message) => {
+                        self.write = WriteState::Writing(Box::pin(async move {
+                            let result = writer.write(message, &mut write_half).await;
+                            (writer, write_half, result)
+                        }));
+                    }
+                    None => {
+                        self.write = WriteState::Idle(writer, write_half);
+                        return Poll::Ready(Ok(()));
+                    }
+                },
+                WriteState::Writing(mut future) => match future.as_mut().poll(cx) {
+                    Poll::Pending => {
+                        self.write = WriteState::Writing(future);
+                        return Poll::Pending;
+                    }
+                    Poll::Ready((writer, write_half, Ok(()))) => {
+                        self.write = WriteState::Idle(writer, write_half);
+                    }
+                    Poll::Ready((_, _, Err(e))) => {
+                        self.queue.clear();
+                        return Poll::Ready(Err(e));
+                    }
+                },
+                WriteState::Done if self.queue.is_empty() => return Poll::Ready(Ok(())),
+                WriteState::Done => {
+                    self.queue.clear();
+                    return Poll::Ready(Err(FrameError::ConnectionClosed));
+                }
+            }
+        }
+    }
+}
+
+impl<S> Stream for WebSocketStream<S>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    type Item = Result<Message, FrameError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        // Pongs and Close replies go out while the application is reading
+        match this.poll_write_queue(cx) {
+            Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+            // Nothing more is read, but the last replies still have to go out
+            Poll::Pending if this.close_received || matches!(this.read, ReadState::Done) => {
+                return Poll::Pending
+            }
+            _ => {}
+        }
+        if this.close_received {
+            return Poll::Ready(None);
+        }
+
+        loop {
+            match mem::replace(&mut this.read, ReadState::Done) {
+                ReadState::Idle(mut reader, mut read_half) => {
+                    this.read = ReadState::Reading(Box::pin(async move {
+                        let result = reader.read(&mut read_half).await;
+                        (reader, read_half, result)
+                    }));
+                }
+                ReadState::Reading(mut future) => {
+                    let (reader, read_half, result) = match future.as_mut().poll(cx) {
+                        Poll::Pending => {
+                            this.read = ReadState::Reading(future);
+                            return Poll::Pending;
+                        }
+                        Poll::Ready(output) => output,
+                    };
+                    let message = match result {
+                        Ok(message) => message,
+                        Err(e) => {
+                            // Fail the connection with the matching status
+                            if let (Some(close), false) =
+                                (CloseFrame::from_error(&e), this.close_sent)
+                            {
+                                this.close_sent = true;
+                                this.queue.push_back(Message::Close(Some(close)));
+                            }
+                            return Poll::Ready(Some(Err(e)));
+                        }
+                    };
+
+                    match &message {
+                        Message::Ping(data) if !this.close_sent => {
+                            this.queue.push_back(Message::Pong(data.clone()));
+                        }
+                        Message::Close(close) => {
+                            this.close_received = true;
+                            if !this.close_sent {
+                                this.close_sent = true;
+                                this.queue.push_back(Message::close_reply(close.clone()));
+                            }
+                            return Poll::Ready(Some(Ok(message)));
+                        }
+                        _ => {}
+                    }
+                    this.read = ReadState::Idle(reader, read_half);
+                    return Poll::Ready(Some(Ok(message)));
+                }
+                ReadState::Done => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl<S> Sink<Message> for WebSocketStream<S>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    type Error = FrameError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), FrameError>> {
+        self.get_mut().poll_write_queue(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: Message) -> Result<(), FrameError> {
+        let this = self.get_mut();
+        // Nothing may follow a Close frame
+        if this.close_sent {
+            return Err(FrameError::ConnectionClosed);
+        }
+        if let Message::Close(_) = message {
+            this.close_sent = true;
+        }
+        this.queue.push_back(message);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), FrameError>> {
+        self.get_mut().poll_write_queue(cx)
+    }
+
+    /// Sends a Close frame unless one was already sent, then shuts down the write side.
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), FrameError>> {
+        let this = self.get_mut();
+        if !this.close_sent {
+            this.close_sent = true;
+            this.queue.push_back(Message::Close(Some(CloseFrame {
+                code: CloseCode::Normal,
+                reason: String::new(),
+            })));
+        }
+        ready!(this.poll_write_queue(cx))?;
+        match &mut this.write {
+            WriteState::Idle(_, write_half) => Pin::new(write_half)
+                .poll_shutdown(cx)
+                .map_err(FrameError::from),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{Frame, Opcode};
+    use crate::handshake::do_client_handshake;
+    use crate::reader::DEFAULT_MAX_MESSAGE_SIZE;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::io::{duplex, DuplexStream};
+
+    fn pair() -> (WebSocketStream<DuplexStream>, WebSocketStream<DuplexStream>) {
+        let (server, client) = duplex(4096);
+        (
+            WebSocketStream::new(server, Role::Server),
+            WebSocketStream::new(client, Role::Client),
+        )
+    }
+
+    fn close(code: CloseCode) -> Message {
+        Message::Close(Some(CloseFrame {
+            code,
+            reason: String::new(),
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_stream_echo() {
+        let (mut server, mut client) = pair();
+        let server = tokio::spawn(async move {
+            let mut received = Vec::new();
+            while let Some(Ok(message)) = server.next().await {
+                if let Message::Text(_) | Message::Binary(_) = message {
+                    server.send(message.clone()).await.unwrap();
+                }
+                received.push(message);
+            }
+            received
+        });
+
+        client
+            .send(Message::Text("Hello".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            Message::Text("Hello".to_string())
+        );
+        client.send(Message::Binary(vec![1, 2, 3])).await.unwrap();
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            Message::Binary(vec![1, 2, 3])
+        );
+
+        client.send(close(CloseCode::Normal)).await.unwrap();
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            close(CloseCode::Normal)
+        );
+        assert!(client.next().await.is_none());
+
+        let received = server.await.unwrap();
+        assert_eq!(received.len(), 3);
+        assert_eq!(received[2], close(CloseCode::Normal));
+    }
+
+    // Replies are written while the stream is polled, so the peer keeps reading until the end
+    fn drain(mut stream: WebSocketStream<DuplexStream>) -> tokio::task::JoinHandle<Vec<Message>> {
+        tokio::spawn(async move {
+            let mut received = Vec::new();
+            while let Some(Ok(message)) = stream.next().await {
+                received.push(message);
+            }
+            received
+        })
+    }
+
+    #[tokio::test]
+    async fn test_stream_ping_reply() {
+        let (server, mut client) = pair();
+        let server = drain(server);
+
+        client.send(Message::Ping(b"hi".to_vec())).await.unwrap();
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            Message::Pong(b"hi".to_vec())
+        );
+
+        client.close().await.unwrap();
+        assert_eq!(
+            server.await.unwrap(),
+            vec![Message::Ping(b"hi".to_vec()), close(CloseCode::Normal)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream_close_reply_protocol_error() {
+        let (server, mut client) = pair();
+        let server = drain(server);
+
+        client.send(close(CloseCode::Abnormal)).await.unwrap();
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            close(CloseCode::Protocol)
+        );
+        assert_eq!(server.await.unwrap(), vec![close(CloseCode::Abnormal)]);
+    }
+
+    #[tokio::test]
+    async fn test_stream_server_close() {
+        let (mut server, mut client) = pair();
+        let client = tokio::spawn(async move {
+            let message = client.next().await.unwrap().unwrap();
+            // Drive the automatic reply out
+            assert!(client.next().await.is_none());
+            message
+        });
+
+        server.close().await.unwrap();
+        assert!(matches!(
+            server.send(Message::Text("late".to_string())).await,
+            Err(FrameError::ConnectionClosed)
+        ));
+        assert_eq!(client.await.unwrap(), close(CloseCode::Normal));
+        assert_eq!(
+            server.next().await.unwrap().unwrap(),
+            close(CloseCode::Normal)
+        );
+        assert!(server.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stream_accept() {
+        let (server, client) = duplex(4096);
+        let server = tokio::spawn(async move {
+            let mut server = WebSocketStream::accept(server, &HandshakeConfig::default())
+                .await
+                .unwrap();
+            let message = server.next().await.unwrap().unwrap();
+            server.send(message).await.unwrap();
+        });
+
+        let (read_half, write_half) = tokio::io::split(client);
+        let mut read_half = BufReader::new(read_half);
+        let mut write_half = BufWriter::new(write_half);
+        do_client_handshake(&mut read_half, &mut write_half, "localhost", "/", &[])
+            .await
+            .unwrap();
+        let mut client = WebSocketStream::from_parts(
+            read_half,
+            write_half,
+            Reader::new(Role::Client, DEFAULT_MAX_MESSAGE_SIZE),
+            Writer::new(Role::Client),
+        );
+
+        client
+            .send(Message::Text("Hello".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            Message::Text("Hello".to_string())
+        );
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stream_protocol_error_close() {
+        let (server, client) = duplex(4096);
+        let mut server = WebSocketStream::new(server, Role::Server);
+        let (client_read, mut client_write) = tokio::io::split(client);
+
+        // Control frames cannot be fragmented
+        let ping = Frame {
+            fin: false,
+            ..Frame::new(Opcode::Ping, Vec::new())
+        };
+        Writer::new(Role::Client)
+            .send_frame(ping, &mut client_write)
+            .await
+            .unwrap();
+        assert!(matches!(
+            server.next().await,
+            Some(Err(FrameError::InvalidControlFin(_)))
+        ));
+        assert!(server.next().await.is_none());
+
+        let mut reader = Reader::new(Role::Client, DEFAULT_MAX_MESSAGE_SIZE);
+        let close = reader.read(&mut BufReader::new(client_read)).await.unwrap();
+        assert!(matches!(
+            close,
+            Message::Close(Some(CloseFrame {
+                code: CloseCode::Protocol,
+                ..
+            }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_stream_with_limits() {
+        let (server, client) = duplex(4096);
+        let limits = Limits {
+            max_frame_size: 4,
+            ..Limits::default()
+        };
+        let mut server = WebSocketStream::with_limits(server, Role::Server, limits);
+        let mut client = WebSocketStream::new(client, Role::Client);
+
+        client
+            .send(Message::Binary(b"too big".to_vec()))
+            .await
+            .unwrap();
+        assert!(matches!(
+            server.next().await,
+            Some(Err(FrameError::FrameTooLarge))
+        ));
+        assert!(server.next().await.is_none());
+        assert!(matches!(
+            client.next().await.unwrap().unwrap(),
+            Message::Close(Some(CloseFrame {
+                code: CloseCode::Size,
+                ..
+            }))
+        ));
+    }
+}
@@ -0,0 +1,605 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use rustls_pemfile::{certs, private_key};
+use thiserror::Error;
+use tokio::sync::watch;
+use tokio::time::MissedTickBehavior;
+use tokio_rustls::rustls::crypto::ring::default_provider;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{ServerConnection, VerifierBuilderError, WebPkiClientVerifier};
+use tokio_rustls::rustls::{self, RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// Errors loading the certificate and key of a TLS listener.
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("Failed to read {0}: {1}")]
+    Io(PathBuf, io::Error),
+    #[error("No certificate found in {0}")]
+    NoCertificates(PathBuf),
+    #[error("No private key found in {0}")]
+    NoPrivateKey(PathBuf),
+    #[error("TLS error: {0}")]
+    Rustls(#[from] rustls::Error),
+    #[error("Invalid client CA bundle: {0}")]
+    Verifier(#[from] VerifierBuilderError),
+}
+
+/// Whether clients authenticate with a certificate, and the CA bundle it must chain to.
+#[derive(Debug, Clone, Default)]
+pub enum ClientAuth {
+    #[default]
+    None,
+    /// Clients may connect without a certificate, one they present must still verify.
+    Optional(PathBuf),
+    Required(PathBuf),
+}
+
+/// PEM files a TLS listener is configured from.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// The certificate chain, leaf first.
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    pub client_auth: ClientAuth,
+}
+
+impl TlsConfig {
+    pub fn new(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        Self {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+            client_auth: ClientAuth::None,
+        }
+    }
+
+    /// Loads the files and builds an acceptor for [`run_tls`](crate::server::run_tls).
+    pub fn acceptor(&self) -> Result<TlsAcceptor, TlsError> {
+        let chain = load_certs(&self.cert_path)?;
+        let key = load_key(&self.key_path)?;
+
+        let provider = Arc::new(default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match &self.client_auth {
+            ClientAuth::None => builder.with_no_client_auth(),
+            ClientAuth::Optional(path) | ClientAuth::Required(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(path)? {
+                    roots.add(cert)?;
+                }
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+                let verifier = match self.client_auth {
+                    ClientAuth::Optional(_) => verifier.allow_unauthenticated().build()?,
+                    _ => verifier.build()?,
+                };
+                builder.with_client_cert_verifier(verifier)
+            }
+        };
+
+        let mut config = builder.with_single_cert(chain, key)?;
+        // The opening handshake is an HTTP/1.1 upgrade
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    /// Builds a new acceptor into `acceptor`, which keeps the old one if that fails.
+    pub fn reload(&self, acceptor: &ReloadableAcceptor) -> Result<(), TlsError> {
+        acceptor.replace(self.acceptor()?);
+        Ok(())
+    }
+
+    fn paths(&self) -> Vec<&Path> {
+        let mut paths = vec![self.cert_path.as_path(), self.key_path.as_path()];
+        if let ClientAuth::Optional(path) | ClientAuth::Required(path) = &self.client_auth {
+            paths.push(path);
+        }
+        paths
+    }
+
+    // Files that cannot be read count as unchanged, the reload would fail anyway
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.paths()
+            .into_iter()
+            .map(|path| path.metadata().and_then(|meta| meta.modified()).ok())
+            .collect()
+    }
+}
+
+/// A [`TlsAcceptor`] that can be swapped while connections are served, cheap to clone.
+///
+/// Each handshake uses the acceptor current when the connection came in, so replacing it
+/// only affects new connections.
+#[derive(Clone)]
+pub struct ReloadableAcceptor {
+    current: Arc<watch::Sender<TlsAcceptor>>,
+}
+
+impl From<TlsAcceptor> for ReloadableAcceptor {
+    fn from(acceptor: TlsAcceptor) -> Self {
+        Self {
+            current: Arc::new(watch::Sender::new(acceptor)),
+        }
+    }
+}
+
+impl ReloadableAcceptor {
+    pub fn current(&self) -> TlsAcceptor {
+        self.current.borrow().clone()
+    }
+
+    pub fn replace(&self, acceptor: TlsAcceptor) {
+        self.current.send_replace(acceptor);
+    }
+}
+
+/// Reloads `acceptor` from `config` on SIGHUP, and when a file of `config` changed since the
+/// last check every `poll`. Runs until dropped, failed reloads are logged and skipped.
+pub async fn reload_on_change(
+    config: TlsConfig,
+    acceptor: ReloadableAcceptor,
+    poll: Duration,
+) -> io::Result<()> {
+    #[cfg(unix)]
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+    let mut ticks = tokio::time::interval(poll);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut seen = config.modified();
+
+    loop {
+        #[cfg(unix)]
+        let hangup = hangup.recv();
+        #[cfg(not(unix))]
+        let hangup = std::future::pending::<Option<()>>();
+
+        tokio::select! {
+            _ = hangup => {}
+            _ = ticks.tick() => {
+                let modified = config.modified();
+                if modified == seen {
+                    continue;
+                }
+                seen = modified;
+            }
+        }
+        match config.reload(&acceptor) {
+            Ok(()) => log::info!("Reloaded TLS certificates"),
+            Err(e) => log::error!("Failed to reload TLS certificates: {}", e),
+        }
+    }
+}
+
+/// Subject of the certificate the client authenticated with, in RFC 4514 form.
+pub(crate) fn peer_subject(connection: &ServerConnection) -> Option<String> {
+    let der = connection.peer_certificates()?.first()?;
+    let (_, cert) = X509Certificate::from_der(der).ok()?;
+    Some(cert.subject().to_string())
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, TlsError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| TlsError::Io(path.to_path_buf(), e))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let chain = certs(&mut open(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| TlsError::Io(path.to_path_buf(), e))?;
+    if chain.is_empty() {
+        return Err(TlsError::NoCertificates(path.to_path_buf()));
+    }
+    Ok(chain)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, TlsError> {
+    private_key(&mut open(path)?)
+        .map_err(|e| TlsError::Io(path.to_path_buf(), e))?
+        .ok_or_else(|| TlsError::NoPrivateKey(path.to_path_buf()))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::frame::Role;
+    use crate::handler::{Echo, Handler, ServerConfig as WsConfig};
+    use crate::handshake::{do_client_handshake, Request};
+    use crate::message::Message;
+    use crate::reader::{Reader, DEFAULT_MAX_MESSAGE_SIZE};
+    use crate::server::{run_tls, Shutdown};
+    use crate::split::WsWriteHalf;
+    use crate::writer::Writer;
+    use rcgen::{
+        generate_simple_self_signed, BasicConstraints, CertificateParams, DnType,
+        ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use std::fs;
+    use std::net::SocketAddr;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::rustls::pki_types::PrivatePkcs8KeyDer;
+    use tokio_rustls::rustls::ClientConfig;
+    use tokio_rustls::TlsConnector;
+
+    /// A self-signed certificate for localhost, written to a fresh directory.
+    pub(crate) struct SelfSigned {
+        pub(crate) dir: PathBuf,
+        pub(crate) config: TlsConfig,
+        pub(crate) cert: CertificateDer<'static>,
+    }
+
+    impl Drop for SelfSigned {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    impl SelfSigned {
+        pub(crate) fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("rws-{}-{}", name, std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+
+            let config = TlsConfig::new(dir.join("cert.pem"), dir.join("key.pem"));
+            let mut certs = Self {
+                dir,
+                config,
+                cert: CertificateDer::from(Vec::new()),
+            };
+            certs.rotate();
+            certs
+        }
+
+        /// Overwrites the files with a new certificate and key.
+        pub(crate) fn rotate(&mut self) {
+            let generated = generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+            fs::write(&self.config.cert_path, generated.cert.pem()).unwrap();
+            fs::write(&self.config.key_path, generated.key_pair.serialize_pem()).unwrap();
+            self.cert = generated.cert.der().clone();
+        }
+
+        pub(crate) fn connector(&self) -> TlsConnector {
+            self.connector_with(None)
+        }
+
+        /// A connector trusting this certificate that presents `identity` when asked.
+        fn connector_with(&self, identity: Option<Identity>) -> TlsConnector {
+            let mut roots = RootCertStore::empty();
+            roots.add(self.cert.clone()).unwrap();
+            let builder = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots);
+            let config = match identity {
+                Some(identity) => builder
+                    .with_client_auth_cert(vec![identity.cert], identity.key)
+                    .unwrap(),
+                None => builder.with_no_client_auth(),
+            };
+            TlsConnector::from(Arc::new(config))
+        }
+
+        /// Writes a fresh CA to the directory and requires or allows client certificates
+        /// it signed.
+        fn with_client_ca(mut self, required: bool) -> (Self, Authority) {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(Vec::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params
+                .distinguished_name
+                .push(DnType::CommonName, "rws test CA");
+            let cert = params.self_signed(&key).unwrap();
+
+            let path = self.dir.join("ca.pem");
+            fs::write(&path, cert.pem()).unwrap();
+            self.config.client_auth = if required {
+                ClientAuth::Required(path)
+            } else {
+                ClientAuth::Optional(path)
+            };
+            (self, Authority { cert, key })
+        }
+    }
+
+    struct Authority {
+        cert: rcgen::Certificate,
+        key: KeyPair,
+    }
+
+    struct Identity {
+        cert: CertificateDer<'static>,
+        key: PrivateKeyDer<'static>,
+    }
+
+    impl Authority {
+        fn issue(&self, name: &str) -> Identity {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(Vec::new()).unwrap();
+            params.distinguished_name.push(DnType::CommonName, name);
+            params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+            let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+            Identity {
+                cert: cert.der().clone(),
+                key: PrivatePkcs8KeyDer::from(key.serialize_der()).into(),
+            }
+        }
+    }
+
+    /// Tells the client who the server thinks it is, then echoes.
+    struct Whoami;
+
+    impl Handler for Whoami {
+        async fn on_open(&mut self, request: &Request, _: Option<&str>, sender: &WsWriteHalf) {
+            let subject = format!("{:?}", request.peer.certificate_subject);
+            let _ = sender.write(Message::Text(subject)).await;
+        }
+
+        async fn on_message(&mut self, message: Message, sender: &WsWriteHalf) {
+            Echo.on_message(message, sender).await
+        }
+    }
+
+    async fn start(certs: &SelfSigned) -> (SocketAddr, Shutdown, ReloadableAcceptor) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = Shutdown::new();
+        let acceptor = ReloadableAcceptor::from(certs.config.acceptor().unwrap());
+        tokio::spawn(run_tls(
+            listener,
+            acceptor.clone(),
+            WsConfig::default(),
+            || Whoami,
+            shutdown.clone(),
+        ));
+        (addr, shutdown, acceptor)
+    }
+
+    type TlsStream = tokio_rustls::client::TlsStream<TcpStream>;
+
+    struct Connection {
+        read_half: tokio::io::BufReader<tokio::io::ReadHalf<TlsStream>>,
+        write_half: tokio::io::WriteHalf<TlsStream>,
+        reader: Reader,
+        writer: Writer,
+    }
+
+    impl Connection {
+        async fn echo(&mut self, text: &str) -> Message {
+            let message = Message::Text(text.to_string());
+            self.writer
+                .write(message, &mut self.write_half)
+                .await
+                .unwrap();
+            self.reader.read(&mut self.read_half).await.unwrap()
+        }
+    }
+
+    /// Connects and returns the first message, or the error that got in the way.
+    async fn connect(
+        addr: SocketAddr,
+        connector: TlsConnector,
+    ) -> Result<(Message, Connection), String> {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let domain = "localhost".try_into().unwrap();
+        let stream = connector
+            .connect(domain, stream)
+            .await
+            .map_err(|e| e.to_string())?;
+        let (read_half, mut write_half) = tokio::io::split(stream);
+        let mut read_half = tokio::io::BufReader::new(read_half);
+        do_client_handshake(&mut read_half, &mut write_half, "localhost", "/", &[])
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut reader = Reader::new(Role::Client, DEFAULT_MAX_MESSAGE_SIZE);
+        let message = reader
+            .read(&mut read_half)
+            .await
+            .map_err(|e| e.to_string())?;
+        let connection = Connection {
+            read_half,
+            write_half,
+            reader,
+            writer: Writer::new(Role::Client),
+        };
+        Ok((message, connection))
+    }
+
+    async fn whoami(addr: SocketAddr, connector: TlsConnector) -> Result<Message, String> {
+        connect(addr, connector).await.map(|(message, _)| message)
+    }
+
+    #[test]
+    fn test_missing_files() {
+        let config = TlsConfig::new("/nonexistent/cert.pem", "/nonexistent/key.pem");
+        assert!(
+            matches!(config.acceptor(), Err(TlsError::Io(path, _)) if path == config.cert_path)
+        );
+    }
+
+    #[test]
+    fn test_key_file_without_key() {
+        let certs = SelfSigned::new("no-key");
+        let config = TlsConfig::new(&certs.config.cert_path, &certs.config.cert_path);
+        assert!(matches!(config.acceptor(), Err(TlsError::NoPrivateKey(_))));
+    }
+
+    #[test]
+    fn test_client_ca_without_certificates() {
+        let mut certs = SelfSigned::new("empty-ca");
+        let path = certs.dir.join("empty.pem");
+        fs::write(&path, "").unwrap();
+        certs.config.client_auth = ClientAuth::Required(path);
+        assert!(matches!(
+            certs.config.acceptor(),
+            Err(TlsError::NoCertificates(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate_subject() {
+        let (certs, authority) = SelfSigned::new("mtls").with_client_ca(true);
+        let (addr, shutdown, _) = start(&certs).await;
+
+        let identity = authority.issue("alice");
+        let subject = whoami(addr, certs.connector_with(Some(identity))).await;
+        assert_eq!(
+            subject.unwrap(),
+            Message::Text("Some(\"CN=alice\")".to_string())
+        );
+        shutdown.trigger();
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate_required() {
+        let (certs, _authority) = SelfSigned::new("mtls-required").with_client_ca(true);
+        let (addr, shutdown, _) = start(&certs).await;
+
+        assert!(whoami(addr, certs.connector()).await.is_err());
+
+        // A certificate from another CA is no better than none
+        let (_, stranger) = SelfSigned::new("mtls-stranger").with_client_ca(true);
+        let identity = stranger.issue("mallory");
+        assert!(whoami(addr, certs.connector_with(Some(identity)))
+            .await
+            .is_err());
+        shutdown.trigger();
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate_optional() {
+        let (certs, authority) = SelfSigned::new("mtls-optional").with_client_ca(false);
+        let (addr, shutdown, _) = start(&certs).await;
+
+        assert_eq!(
+            whoami(addr, certs.connector()).await.unwrap(),
+            Message::Text("None".to_string())
+        );
+        let identity = authority.issue("bob");
+        assert_eq!(
+            whoami(addr, certs.connector_with(Some(identity)))
+                .await
+                .unwrap(),
+            Message::Text("Some(\"CN=bob\")".to_string())
+        );
+        shutdown.trigger();
+    }
+
+    #[tokio::test]
+    async fn test_reload_keeps_connections() {
+        let mut certs = SelfSigned::new("reload");
+        let (addr, shutdown, acceptor) = start(&certs).await;
+        let old = certs.connector();
+        let (_, mut connection) = connect(addr, old.clone()).await.unwrap();
+
+        certs.rotate();
+        certs.config.reload(&acceptor).unwrap();
+
+        // New connections get the new certificate, the open one carries on
+        assert!(connect(addr, old).await.is_err());
+        assert!(connect(addr, certs.connector()).await.is_ok());
+        assert_eq!(
+            connection.echo("still here").await,
+            Message::Text("still here".to_string())
+        );
+        shutdown.trigger();
+    }
+
+    #[tokio::test]
+    async fn test_failed_reload_keeps_acceptor() {
+        let certs = SelfSigned::new("reload-broken");
+        let (addr, shutdown, acceptor) = start(&certs).await;
+
+        fs::write(&certs.config.key_path, "half a key").unwrap();
+        assert!(matches!(
+            certs.config.reload(&acceptor),
+            Err(TlsError::NoPrivateKey(_))
+        ));
+        assert!(connect(addr, certs.connector()).await.is_ok());
+        shutdown.trigger();
+    }
+
+    #[tokio::test]
+    async fn test_reload_on_file_change() {
+        let mut certs = SelfSigned::new("reload-watch");
+        let (addr, shutdown, acceptor) = start(&certs).await;
+        let watcher = tokio::spawn(reload_on_change(
+            certs.config.clone(),
+            acceptor,
+            Duration::from_millis(10),
+        ));
+        // Let the watcher see the files as they were
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        certs.rotate();
+        // Coarse file system timestamps might not tell the writes apart
+        let later = SystemTime::now() + Duration::from_secs(60);
+        for path in certs.config.paths() {
+            File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(later)
+                .unwrap();
+        }
+
+        let reloaded = async {
+            while connect(addr, certs.connector()).await.is_err() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), reloaded)
+            .await
+            .unwrap();
+        watcher.abort();
+        shutdown.trigger();
+    }
+
+    #[tokio::test]
+    async fn test_tls_echo() {
+        let certs = SelfSigned::new("echo");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = Shutdown::new();
+        let acceptor = certs.config.acceptor().unwrap();
+        let server = tokio::spawn(run_tls(
+            listener,
+            acceptor,
+            WsConfig::default(),
+            || Echo,
+            shutdown.clone(),
+        ));
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let domain = "localhost".try_into().unwrap();
+        let stream = certs.connector().connect(domain, stream).await.unwrap();
+        let (read_half, mut write_half) = tokio::io::split(stream);
+        let mut read_half = tokio::io::BufReader::new(read_half);
+        do_client_handshake(&mut read_half, &mut write_half, "localhost", "/", &[])
+            .await
+            .unwrap();
+
+        let mut reader = Reader::new(Role::Client, DEFAULT_MAX_MESSAGE_SIZE);
+        let mut writer = Writer::new(Role::Client);
+        let message = Message::Text("Hello over TLS".to_string());
+        writer
+            .write(message.clone(), &mut write_half)
+            .await
+            .unwrap();
+        assert_eq!(reader.read(&mut read_half).await.unwrap(), message);
+
+        shutdown.trigger();
+        assert!(matches!(
+            reader.read(&mut read_half).await.unwrap(),
+            Message::Close(_)
+        ));
+        writer
+            .write(Message::Close(None), &mut write_half)
+            .await
+            .unwrap();
+        server.await.unwrap().unwrap();
+    }
+}
@@ -1,45 +1,354 @@
-use crate::frame::{Frame, FrameError};
-use tokio::io::AsyncWriteExt;
+use crate::extension::Extension;
+use crate::frame::{apply_mask, Frame, FrameError, Opcode, Role};
+use crate::message::Message;
+use crate::reader::validate_text;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 
-pub struct Writer {}
+/// Default fragment size of servers and clients, see [`Writer::set_fragment_size`].
+pub const DEFAULT_FRAGMENT_SIZE: usize = 1024 * 1024;
+
+// Masking happens through a fixed buffer, its size must stay a multiple of 4
+const MASK_CHUNK_SIZE: usize = 4096;
+
+/// Produces the masking key for each frame a client sends.
+pub type MaskSource = Box<dyn FnMut() -> [u8; 4] + Send + Sync>;
+
+/// Serializes frames, applying negotiated extensions and client masking.
+pub struct Writer {
+    role: Role,
+    extensions: Vec<Box<dyn Extension>>,
+    mask_source: MaskSource,
+    fragment_size: Option<usize>,
+}
 
 impl Writer {
+    pub fn new(role: Role) -> Self {
+        Self {
+            role,
+            extensions: Vec::new(),
+            mask_source: Box::new(rand::random),
+            fragment_size: None,
+        }
+    }
+
+    /// Replaces the random masking keys, mostly useful to get deterministic output in tests.
+    pub fn set_mask_source(
+        &mut self,
+        mask_source: impl FnMut() -> [u8; 4] + Send + Sync + 'static,
+    ) {
+        self.mask_source = Box::new(mask_source);
+    }
+
+    /// Splits Text and Binary messages larger than `fragment_size` into frames of that size,
+    /// `None` sends every message in a single frame.
+    ///
+    /// # Panics
+    ///
+    /// If `fragment_size` is `Some(0)`.
+    pub fn set_fragment_size(&mut self, fragment_size: Option<usize>) {
+        assert_ne!(fragment_size, Some(0), "Fragment size must not be 0");
+        self.fragment_size = fragment_size;
+    }
+
+    pub fn add_extension(&mut self, extension: Box<dyn Extension>) {
+        self.extensions.push(extension);
+    }
+
+    pub async fn write(
+        &mut self,
+        message: Message,
+        writer: &mut (impl AsyncWriteExt + Unpin),
+    ) -> Result<(), FrameError> {
+        for frame in self.fragment(message)? {
+            self.write_encoded(&frame, writer).await?;
+        }
+        Ok(())
+    }
+
+    /// Encodes `message` with the negotiated extensions and splits it into the frames to
+    /// write with [`Writer::write_encoded`], see [`Writer::set_fragment_size`].
+    ///
+    /// Control frames may be written between the fragments, RFC 6455 section 5.4.
+    pub fn fragment(&mut self, message: Message) -> Result<Fragmented, FrameError> {
+        let frame = self.encode(Frame::from(message))?;
+        let size = match self.fragment_size {
+            Some(size) if !frame.opcode.is_control() => size,
+            _ => usize::MAX,
+        };
+        Ok(Fragmented {
+            frame: Some(frame),
+            offset: 0,
+            size,
+        })
+    }
+
+    /// Sends everything `source` yields as one Text or Binary message, split into frames of
+    /// `frame_size` bytes so at most two of them are held in memory.
+    ///
+    /// Text is checked to be valid UTF-8 before each frame goes out. Once the first frame was
+    /// written an error leaves the message unfinished, so the connection must be failed.
+    ///
+    /// # Panics
+    ///
+    /// If `opcode` is not Text or Binary, or `frame_size` is 0.
+    pub async fn write_from(
+        &mut self,
+        opcode: Opcode,
+        source: &mut (impl AsyncRead + Unpin),
+        frame_size: usize,
+        writer: &mut (impl AsyncWriteExt + Unpin),
+    ) -> Result<(), FrameError> {
+        let mut frames = StreamedFrames::new(opcode, frame_size);
+        while let Some(frame) = frames.next(source).await? {
+            self.send_frame(frame, writer).await?;
+        }
+        Ok(())
+    }
+
+    /// Encodes `frame` with the negotiated extensions and writes it for our role.
+    pub async fn send_frame(
+        &mut self,
+        frame: Frame,
+        writer: &mut (impl AsyncWriteExt + Unpin),
+    ) -> Result<(), FrameError> {
+        let frame = self.encode(frame)?;
+        self.write_encoded(&frame, writer).await
+    }
+
+    /// Writes a frame the extensions already encoded for our role, masking it as a client.
+    pub async fn write_encoded(
+        &mut self,
+        frame: &Frame,
+        writer: &mut (impl AsyncWriteExt + Unpin),
+    ) -> Result<(), FrameError> {
+        match self.role {
+            Role::Server => Self::write_frame(frame, writer).await,
+            Role::Client => {
+                let mask = (self.mask_source)();
+                Self::write_masked_frame(frame, mask, writer).await
+            }
+        }
+    }
+
+    fn encode(&mut self, frame: Frame) -> Result<Frame, FrameError> {
+        let mut frame = frame;
+        for extension in self.extensions.iter_mut() {
+            frame = extension.encode(frame)?;
+        }
+        Ok(frame)
+    }
+
+    /// Writes `frame` as is, without a mask.
     pub async fn write_frame(
         frame: &Frame,
         writer: &mut (impl AsyncWriteExt + Unpin),
     ) -> Result<(), FrameError> {
-        let mut first_byte = if frame.fin { 0b1000_0000 } else { 0b0000_0000 };
+        Self::write_header(frame, None, writer).await?;
+        writer.write_all(&frame.data).await?;
+        writer.flush().await?;
 
-        first_byte |= frame.opcode as u8;
+        Ok(())
+    }
+
+    /// Writes `frame` masked with `mask`, as clients must.
+    pub async fn write_masked_frame(
+        frame: &Frame,
+        mask: [u8; 4],
+        writer: &mut (impl AsyncWriteExt + Unpin),
+    ) -> Result<(), FrameError> {
+        Self::write_header(frame, Some(mask), writer).await?;
+
+        // Mask a copy so the caller's frame stays untouched
+        let mut buffer = [0; MASK_CHUNK_SIZE];
+        for chunk in frame.data.chunks(MASK_CHUNK_SIZE) {
+            let masked = &mut buffer[..chunk.len()];
+            masked.copy_from_slice(chunk);
+            apply_mask(masked, mask);
+            writer.write_all(masked).await?;
+        }
+        writer.flush().await?;
+
+        Ok(())
+    }
+
+    async fn write_header(
+        frame: &Frame,
+        mask: Option<[u8; 4]>,
+        writer: &mut (impl AsyncWriteExt + Unpin),
+    ) -> Result<(), FrameError> {
+        let mut first_byte = if frame.fin { 0b1000_0000 } else { 0b0000_0000 };
+        first_byte |= frame.rsv;
+        first_byte |= u8::from(frame.opcode);
         writer.write_all(&[first_byte]).await?;
 
+        let mask_bit = if mask.is_some() {
+            0b1000_0000
+        } else {
+            0b0000_0000
+        };
         if frame.len <= 125 {
-            writer.write_all(&[frame.len as u8]).await?;
+            writer.write_all(&[mask_bit | frame.len as u8]).await?;
         } else if frame.len <= u16::MAX as usize {
-            writer.write_all(&[126]).await?;
+            writer.write_all(&[mask_bit | 126]).await?;
             writer.write_all(&(frame.len as u16).to_be_bytes()).await?;
         } else {
-            writer.write_all(&[127]).await?;
+            writer.write_all(&[mask_bit | 127]).await?;
             writer.write_all(&(frame.len as u64).to_be_bytes()).await?;
         };
 
-        writer.write_all(&frame.data).await?;
-        writer.flush().await?;
+        if let Some(mask) = mask {
+            writer.write_all(&mask).await?;
+        }
 
         Ok(())
     }
 }
 
+/// The frames an encoded message goes out in, see [`Writer::fragment`].
+pub struct Fragmented {
+    frame: Option<Frame>,
+    offset: usize,
+    size: usize,
+}
+
+impl Iterator for Fragmented {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        let frame = self.frame.as_mut()?;
+        let first = self.offset == 0;
+        // Extension bits only belong on the first frame of a message
+        let (opcode, rsv) = if first {
+            (frame.opcode, frame.rsv)
+        } else {
+            (Opcode::Continuation, 0)
+        };
+
+        if frame.data.len() - self.offset <= self.size {
+            let mut last = self.frame.take()?;
+            if !first {
+                last.data.drain(..self.offset);
+                last.len = last.data.len();
+                last.opcode = opcode;
+                last.rsv = rsv;
+            }
+            return Some(last);
+        }
+
+        let data = frame.data[self.offset..self.offset + self.size].to_vec();
+        self.offset += self.size;
+        Some(Frame {
+            fin: false,
+            rsv,
+            opcode,
+            len: data.len(),
+            data,
+        })
+    }
+}
+
+/// Cuts the bytes of a reader into the frames of one message, see [`Writer::write_from`].
+pub(crate) struct StreamedFrames {
+    opcode: Opcode,
+    frame_size: usize,
+    incomplete: Option<utf8::Incomplete>,
+    // Read one frame ahead, which tells whether the previous one is the last
+    ahead: Option<Frame>,
+    done: bool,
+}
+
+impl StreamedFrames {
+    pub(crate) fn new(opcode: Opcode, frame_size: usize) -> Self {
+        assert!(
+            matches!(opcode, Opcode::Text | Opcode::Binary),
+            "Only Text and Binary messages can be streamed"
+        );
+        assert!(frame_size > 0, "Frame size must not be 0");
+        Self {
+            opcode,
+            frame_size,
+            incomplete: None,
+            ahead: None,
+            done: false,
+        }
+    }
+
+    /// The next frame to send, still to be encoded, or `None` after the final one.
+    pub(crate) async fn next(
+        &mut self,
+        source: &mut (impl AsyncRead + Unpin),
+    ) -> Result<Option<Frame>, FrameError> {
+        if self.done {
+            return Ok(None);
+        }
+        let mut frame = match self.ahead.take() {
+            Some(frame) => frame,
+            None => Frame::new(self.opcode, fill(source, self.frame_size).await?),
+        };
+        let next = fill(source, self.frame_size).await?;
+        frame.fin = next.is_empty();
+
+        if self.opcode == Opcode::Text {
+            validate_text(&mut self.incomplete, &frame.data, |_| {})?;
+            if frame.fin && self.incomplete.is_some() {
+                return Err(FrameError::InvalidUTF8);
+            }
+        }
+        if frame.fin {
+            self.done = true;
+        } else {
+            self.ahead = Some(Frame::new(Opcode::Continuation, next));
+        }
+        Ok(Some(frame))
+    }
+}
+
+/// Reads from `source` until `size` bytes arrived or it ends.
+async fn fill(source: &mut (impl AsyncRead + Unpin), size: usize) -> Result<Vec<u8>, FrameError> {
+    let mut buffer = vec![0; size];
+    let mut filled = 0;
+    while filled < size {
+        match source.read(&mut buffer[filled..]).await? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    buffer.truncate(filled);
+    Ok(buffer)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::frame::Opcode;
+    use crate::deflate::{Deflater, Inflater};
+    use crate::frame::RSV1;
+    use crate::reader::{Reader, DEFAULT_MAX_MESSAGE_SIZE};
+    use std::io::Cursor;
+
+    /// Streams `data` through a server writer and reads the frames back.
+    async fn stream(opcode: Opcode, data: &[u8], frame_size: usize) -> Vec<Frame> {
+        let mut buffer = Vec::new();
+        let mut writer = Writer::new(Role::Server);
+        writer
+            .write_from(opcode, &mut Cursor::new(data), frame_size, &mut buffer)
+            .await
+            .unwrap();
+
+        let reader = Reader::new(Role::Client, DEFAULT_MAX_MESSAGE_SIZE);
+        let mut written = Cursor::new(buffer);
+        let mut frames = Vec::new();
+        while (written.position() as usize) < written.get_ref().len() {
+            frames.push(reader.read_frame(&mut written).await.unwrap());
+        }
+        frames
+    }
 
     #[tokio::test]
     async fn test_write_small_frame() {
         let mut buffer = Vec::new();
         let frame = Frame {
             fin: true,
+            rsv: 0,
             opcode: Opcode::Text,
             len: 5,
             data: b"Hello".to_vec(),
@@ -57,6 +366,7 @@ mod tests {
         let data = vec![0; 256];
         let frame = Frame {
             fin: true,
+            rsv: 0,
             opcode: Opcode::Binary,
             len: 256,
             data,
@@ -68,4 +378,259 @@ mod tests {
         assert_eq!(buffer[1], 126); // Extended payload length indicator
         assert_eq!(u16::from_be_bytes([buffer[2], buffer[3]]), 256);
     }
+
+    #[tokio::test]
+    async fn test_write_compressed_frame() {
+        let mut buffer = Vec::new();
+        let mut writer = Writer::new(Role::Server);
+        writer.add_extension(Box::new(Deflater::new(false, 15)));
+
+        let frame = Frame::new(Opcode::Text, b"Hello".to_vec());
+        writer.send_frame(frame, &mut buffer).await.unwrap();
+
+        assert_eq!(buffer[0], 0b1100_0001); // FIN + RSV1 + Text frame
+        assert_eq!(buffer[1] as usize, buffer.len() - 2);
+        let data = Inflater::new(false).decompress(&buffer[2..], 1024).unwrap();
+        assert_eq!(data, b"Hello");
+    }
+
+    #[tokio::test]
+    async fn test_write_control_frame_uncompressed() {
+        let mut buffer = Vec::new();
+        let mut writer = Writer::new(Role::Server);
+        writer.add_extension(Box::new(Deflater::new(false, 15)));
+
+        let frame = Frame::new(Opcode::Ping, b"ping".to_vec());
+        writer.send_frame(frame, &mut buffer).await.unwrap();
+
+        assert_eq!(buffer[0], 0b1000_1001); // FIN + Ping frame
+        assert_eq!(&buffer[2..], b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_write_masked_frame() {
+        let mut buffer = Vec::new();
+        let frame = Frame::new(Opcode::Text, b"Hello".to_vec());
+
+        Writer::write_masked_frame(&frame, [0x37, 0xfa, 0x21, 0x3d], &mut buffer)
+            .await
+            .unwrap();
+
+        // RFC 6455 section 5.7, a single-frame masked text message
+        assert_eq!(
+            buffer,
+            [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58]
+        );
+        assert_eq!(frame.data, b"Hello");
+    }
+
+    #[tokio::test]
+    async fn test_write_client_frame() {
+        let mut buffer = Vec::new();
+        let mut writer = Writer::new(Role::Client);
+        writer
+            .write(Message::Binary(vec![0; 256]), &mut buffer)
+            .await
+            .unwrap();
+
+        assert_eq!(buffer[0], 0b1000_0010); // FIN + Binary frame
+        assert_eq!(buffer[1], 0b1000_0000 | 126); // MASK + extended payload length
+        assert_eq!(u16::from_be_bytes([buffer[2], buffer[3]]), 256);
+
+        let mask = [buffer[4], buffer[5], buffer[6], buffer[7]];
+        let mut payload = buffer[8..].to_vec();
+        apply_mask(&mut payload, mask);
+        assert_eq!(payload, vec![0; 256]);
+    }
+
+    #[tokio::test]
+    async fn test_write_client_frame_with_mask_source() {
+        let mut buffer = Vec::new();
+        let mut writer = Writer::new(Role::Client);
+        let mut next = 0u8;
+        writer.set_mask_source(move || {
+            next += 1;
+            [next; 4]
+        });
+
+        writer
+            .write(Message::Text("Hi".to_string()), &mut buffer)
+            .await
+            .unwrap();
+        writer
+            .write(Message::Text("Hi".to_string()), &mut buffer)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            buffer,
+            [
+                0x81,
+                0x82,
+                1,
+                1,
+                1,
+                1,
+                b'H' ^ 1,
+                b'i' ^ 1, // first key
+                0x81,
+                0x82,
+                2,
+                2,
+                2,
+                2,
+                b'H' ^ 2,
+                b'i' ^ 2, // second key
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_masked_large_frame() {
+        let mut buffer = Vec::new();
+        let data: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+        let frame = Frame::new(Opcode::Binary, data.clone());
+
+        Writer::write_masked_frame(&frame, [1, 2, 3, 4], &mut buffer)
+            .await
+            .unwrap();
+
+        assert_eq!(buffer[1], 0b1000_0000 | 126);
+        assert_eq!(&buffer[4..8], [1, 2, 3, 4]);
+        let mut payload = buffer[8..].to_vec();
+        apply_mask(&mut payload, [1, 2, 3, 4]);
+        assert_eq!(payload, data);
+        assert_eq!(frame.data, data);
+    }
+
+    #[tokio::test]
+    async fn test_write_from_fragments() {
+        let frames = stream(Opcode::Binary, b"abcdefg", 3).await;
+
+        let sent: Vec<_> = frames
+            .iter()
+            .map(|frame| (frame.fin, frame.opcode, frame.data.as_slice()))
+            .collect();
+        assert_eq!(
+            sent,
+            [
+                (false, Opcode::Binary, &b"abc"[..]),
+                (false, Opcode::Continuation, &b"def"[..]),
+                (true, Opcode::Continuation, &b"g"[..]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_from_frame_boundary() {
+        // No empty frame trails a source that ends on a frame boundary
+        let frames = stream(Opcode::Binary, b"abcdef", 3).await;
+        assert_eq!(frames.len(), 2);
+        assert!(frames[1].fin);
+        assert_eq!(frames[1].data, b"def");
+
+        let frames = stream(Opcode::Text, b"", 3).await;
+        assert_eq!(frames, [Frame::new(Opcode::Text, Vec::new())]);
+    }
+
+    #[tokio::test]
+    async fn test_write_from_reads_back() {
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        let mut buffer = Vec::new();
+        let mut writer = Writer::new(Role::Client);
+        writer
+            .write_from(Opcode::Binary, &mut Cursor::new(&data), 4096, &mut buffer)
+            .await
+            .unwrap();
+
+        let mut reader = Reader::new(Role::Server, DEFAULT_MAX_MESSAGE_SIZE);
+        let message = reader.read(&mut Cursor::new(buffer)).await.unwrap();
+        assert_eq!(message, Message::Binary(data));
+    }
+
+    #[tokio::test]
+    async fn test_write_from_text_split_character() {
+        // The two byte 'é' is cut in half by the frame boundary
+        let frames = stream(Opcode::Text, "aé".as_bytes(), 2).await;
+        assert_eq!(frames[0].data, b"a\xc3");
+        assert_eq!(frames[1].data, b"\xa9");
+    }
+
+    #[tokio::test]
+    async fn test_write_from_invalid_text() {
+        let mut buffer = Vec::new();
+        let mut writer = Writer::new(Role::Server);
+        let mut source = Cursor::new(b"abc\xff".to_vec());
+        let written = writer
+            .write_from(Opcode::Text, &mut source, 3, &mut buffer)
+            .await;
+        assert!(matches!(written, Err(FrameError::InvalidUTF8)));
+        // The valid first frame already went out
+        assert_eq!(buffer, [0x01, 3, b'a', b'b', b'c']);
+
+        // Nor may the source end in the middle of a character
+        let mut source = Cursor::new(vec![b'a', 0xc3]);
+        let written = writer
+            .write_from(Opcode::Text, &mut source, 3, &mut Vec::new())
+            .await;
+        assert!(matches!(written, Err(FrameError::InvalidUTF8)));
+    }
+
+    #[tokio::test]
+    async fn test_write_fragmented() {
+        let mut buffer = Vec::new();
+        let mut writer = Writer::new(Role::Server);
+        writer.set_fragment_size(Some(3));
+        writer
+            .write(Message::Text("abcdefg".to_string()), &mut buffer)
+            .await
+            .unwrap();
+        // Control frames are never split
+        writer
+            .write(Message::Ping(b"ping".to_vec()), &mut buffer)
+            .await
+            .unwrap();
+
+        let reader = Reader::new(Role::Client, DEFAULT_MAX_MESSAGE_SIZE);
+        let mut written = Cursor::new(buffer);
+        let mut frames = Vec::new();
+        for _ in 0..4 {
+            let frame = reader.read_frame(&mut written).await.unwrap();
+            frames.push((frame.fin, frame.opcode, frame.data));
+        }
+        assert_eq!(
+            frames,
+            [
+                (false, Opcode::Text, b"abc".to_vec()),
+                (false, Opcode::Continuation, b"def".to_vec()),
+                (true, Opcode::Continuation, b"g".to_vec()),
+                (true, Opcode::Ping, b"ping".to_vec()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_fragmented_compressed() {
+        let mut buffer = Vec::new();
+        let mut writer = Writer::new(Role::Client);
+        writer.add_extension(Box::new(Deflater::new(false, 15)));
+        writer.set_fragment_size(Some(4));
+        let text = "Hello, Hello, Hello, Hello, Hello".to_string();
+        let fragments: Vec<_> = writer
+            .fragment(Message::Text(text.clone()))
+            .unwrap()
+            .collect();
+        assert!(fragments.len() > 1);
+        // The message is compressed as a whole, only its first frame is marked
+        assert_eq!(fragments[0].rsv, RSV1);
+        assert!(fragments[1..].iter().all(|frame| frame.rsv == 0));
+
+        for frame in &fragments {
+            writer.write_encoded(frame, &mut buffer).await.unwrap();
+        }
+        let mut reader = Reader::new(Role::Server, DEFAULT_MAX_MESSAGE_SIZE);
+        reader.add_extension(Box::new(Inflater::new(false)));
+        let message = reader.read(&mut Cursor::new(buffer)).await.unwrap();
+        assert_eq!(message, Message::Text(text));
+    }
 }